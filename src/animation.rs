@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+use std::io::Cursor;
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat};
+use serde::{Deserialize, Serialize};
+use crate::load_image::{composite, Color, ImageLimits, PreprocessError};
+
+/// Most frames a single request may sample from an animation.
+pub const MAX_SAMPLED_FRAMES: usize = 32;
+
+/// Animations with more frames than this are rejected rather than walked twice. Every frame is
+/// decoded onto the full canvas just to read its delay, so the pixel limit bounds it as well.
+const MAX_ANIMATION_FRAMES: usize = 1_000;
+
+/// A frame picked from an animation, already flattened onto the background.
+pub struct SampledFrame {
+    pub index: usize,
+    pub timestamp_ms: u64,
+    pub image: DynamicImage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameCaption {
+    pub index: usize,
+    pub timestamp_ms: u64,
+    pub caption: String,
+}
+
+/// Opens the frame iterator of an animated GIF or WebP with the canvas size, or returns `None` for
+/// anything else.
+fn open_frames<'a>(data: &'a [u8], limits: &ImageLimits) -> image::ImageResult<Option<(image::Frames<'a>, (u32, u32))>> {
+    match image::guess_format(data) {
+        Ok(ImageFormat::Gif) => {
+            let mut decoder = GifDecoder::new(Cursor::new(data))?;
+            decoder.set_limits(limits.to_decoder_limits())?;
+            let dimensions = decoder.dimensions();
+            Ok(Some((decoder.into_frames(), dimensions)))
+        }
+        Ok(ImageFormat::WebP) => {
+            let mut decoder = WebPDecoder::new(Cursor::new(data))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.set_limits(limits.to_decoder_limits())?;
+            let dimensions = decoder.dimensions();
+            Ok(Some((decoder.into_frames(), dimensions)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Picks `count` frames spread evenly across an animation, with the time each one is shown.
+/// Returns `None` when the upload is not animated.
+///
+/// The animation is walked twice, once to learn its length and timing and once to keep the
+/// sampled frames, so only `count` frames are ever held in memory. Since every frame is decoded
+/// onto the whole canvas, all frames together may not exceed `limits.max_pixels`.
+pub fn sample_frames(data: &[u8], count: usize, background: Color, limits: &ImageLimits) -> Result<Option<Vec<SampledFrame>>, PreprocessError> {
+    if count == 0 || count > MAX_SAMPLED_FRAMES {
+        return Err(PreprocessError::InvalidRequest(format!(
+            "frame count must be between 1 and {MAX_SAMPLED_FRAMES}, got {count}"
+        )));
+    }
+    let invalid = |e: image::ImageError| PreprocessError::Invalid(e.to_string());
+
+    let Some((frames, (width, height))) = open_frames(data, limits).map_err(invalid)? else {
+        return Ok(None);
+    };
+    let canvas = (width as u64 * height as u64).max(1);
+    let max_frames = (limits.max_pixels / canvas).min(MAX_ANIMATION_FRAMES as u64) as usize;
+    let mut timestamps = Vec::new();
+    let mut elapsed_ms = 0u64;
+    for frame in frames.take(max_frames + 1) {
+        let frame = frame.map_err(invalid)?;
+        if timestamps.len() == max_frames {
+            return Err(PreprocessError::TooLarge {
+                width,
+                height,
+                reason: format!("animation has more than {max_frames} frames of this size"),
+            });
+        }
+        timestamps.push(elapsed_ms);
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        elapsed_ms += (numer / denom.max(1)) as u64;
+    }
+    if timestamps.len() < 2 {
+        return Ok(None);
+    }
+
+    let total = timestamps.len();
+    let wanted: Vec<usize> = (0..count.min(total)).map(|i| i * total / count.min(total)).collect();
+    let Some((frames, _)) = open_frames(data, limits).map_err(invalid)? else {
+        return Ok(None);
+    };
+    let mut sampled = Vec::with_capacity(wanted.len());
+    for (index, frame) in frames.enumerate().take(wanted.last().map_or(0, |last| last + 1)) {
+        let frame = frame.map_err(invalid)?;
+        if wanted.binary_search(&index).is_ok() {
+            sampled.push(SampledFrame {
+                index,
+                timestamp_ms: timestamps[index],
+                image: DynamicImage::ImageRgb8(composite(&frame.into_buffer(), background)),
+            });
+        }
+    }
+    Ok(Some(sampled))
+}
+
+/// The distinct captions in order of first appearance, so a looping animation reads as a short
+/// summary rather than a list of repeats.
+pub fn distinct_captions(frames: &[FrameCaption]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    frames
+        .iter()
+        .filter(|frame| seen.insert(frame.caption.as_str()))
+        .map(|frame| frame.caption.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, Rgba, RgbaImage};
+
+    fn gif(frames: usize, size: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = GifEncoder::new(&mut data);
+        let frames = (0..frames).map(|i| {
+            let shade = (i * 255 / frames) as u8;
+            let image = RgbaImage::from_pixel(size, size, Rgba([shade, shade, shade, 255]));
+            Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(100, 1))
+        });
+        encoder.encode_frames(frames).unwrap();
+        drop(encoder);
+        data
+    }
+
+    #[test]
+    fn samples_evenly_spaced_frames() {
+        let frames = sample_frames(&gif(8, 4), 4, Color([255; 3]), &ImageLimits::default()).unwrap().unwrap();
+        let picked: Vec<_> = frames.iter().map(|frame| (frame.index, frame.timestamp_ms)).collect();
+        assert_eq!(picked, vec![(0, 0), (2, 200), (4, 400), (6, 600)]);
+    }
+
+    #[test]
+    fn still_images_are_not_animated() {
+        assert!(sample_frames(&gif(1, 4), 4, Color([255; 3]), &ImageLimits::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn decoded_pixels_are_bounded_by_the_limits() {
+        let limits = ImageLimits { max_pixels: 16 * 5, ..ImageLimits::default() };
+        assert!(sample_frames(&gif(5, 4), 2, Color([255; 3]), &limits).is_ok());
+        let result = sample_frames(&gif(6, 4), 2, Color([255; 3]), &limits);
+        assert!(matches!(result, Err(PreprocessError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn distinct_captions_keep_first_appearance() {
+        let frame = |caption: &str| FrameCaption { index: 0, timestamp_ms: 0, caption: caption.to_string() };
+        let frames = [frame("a cat"), frame("a dog"), frame("a cat")];
+        assert_eq!(distinct_captions(&frames), vec!["a cat", "a dog"]);
+    }
+}
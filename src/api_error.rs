@@ -0,0 +1,82 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use crate::load_image::PreprocessError;
+
+/// Error returned by the HTTP endpoints, rendered as a JSON body with an `error` message.
+pub struct ApiError {
+    status: StatusCode,
+    body: serde_json::Value,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl ToString) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<PreprocessError>() {
+            Some(PreprocessError::TooLarge { width, height, reason }) => Self {
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+                body: json!({
+                    "error": e.to_string(),
+                    "width": width,
+                    "height": height,
+                    "reason": reason,
+                }),
+            },
+            Some(PreprocessError::Invalid(_)) => Self::new(StatusCode::UNPROCESSABLE_ENTITY, e),
+            Some(PreprocessError::InvalidRequest(_)) => Self::new(StatusCode::UNPROCESSABLE_ENTITY, e),
+            Some(PreprocessError::NotCached { .. }) => Self::new(StatusCode::NOT_FOUND, e),
+            None => Self::new(StatusCode::INTERNAL_SERVER_ERROR, e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rerank::{check_best_of, MAX_BEST_OF};
+
+    fn status(e: PreprocessError) -> StatusCode {
+        ApiError::from(anyhow::Error::from(e)).status
+    }
+
+    #[test]
+    fn preprocess_errors_map_to_client_errors() {
+        let too_large = PreprocessError::TooLarge { width: 100_000, height: 100_000, reason: "width exceeds 16384".to_string() };
+        assert_eq!(status(too_large), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(status(PreprocessError::Invalid("truncated".to_string())), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(status(PreprocessError::InvalidRequest("unknown model".to_string())), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn out_of_range_best_of_is_an_invalid_request() {
+        for n in [0, MAX_BEST_OF + 1] {
+            assert_eq!(status(check_best_of(n).unwrap_err()), StatusCode::UNPROCESSABLE_ENTITY, "best_of {n}");
+        }
+        assert!(check_best_of(1).is_ok());
+        assert!(check_best_of(MAX_BEST_OF).is_ok());
+    }
+
+    #[test]
+    fn too_large_reports_the_declared_size() {
+        let e = PreprocessError::TooLarge { width: 100_000, height: 100_000, reason: "width exceeds 16384".to_string() };
+        let error = ApiError::from(anyhow::Error::from(e));
+        assert_eq!(error.body["width"], 100_000);
+        assert_eq!(error.body["height"], 100_000);
+        assert!(error.body["error"].as_str().unwrap().contains("100000x100000"));
+    }
+}
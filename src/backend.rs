@@ -0,0 +1,178 @@
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{blip, quantized_blip};
+use tokenizers::Tokenizer;
+use crate::blip_config::{safetensors_parameters, safetensors_shapes, BlipSpec};
+use crate::load_image::ImageSpec;
+use crate::models::ModelConfig;
+use anyhow::Error as E;
+
+/// Backend of registry entries that do not name one.
+pub const DEFAULT_BACKEND: &str = "blip";
+
+/// Names accepted as the `backend` of a registry entry.
+pub const BACKENDS: &[&str] = &["blip"];
+
+/// Token ids that frame a generated caption.
+#[derive(Debug, Clone, Copy)]
+pub struct SpecialTokens {
+    /// Fed to the decoder to start a caption.
+    pub bos: u32,
+    /// Ends the caption when sampled.
+    pub eos: u32,
+}
+
+/// A vision-language model that can caption images: a vision encoder producing image embeddings
+/// and a text decoder generating from them one step at a time. `Captioner` runs the sampling loop
+/// and token streaming on top, so a backend only has to provide the model pieces.
+pub trait CaptionBackend: Send + Sync {
+    /// A copy sharing the weights but with its own decoding state, one per request.
+    fn boxed_clone(&self) -> Box<dyn CaptionBackend>;
+
+    /// How images must be resized and normalized before [`Self::encode`].
+    fn image_spec(&self) -> &ImageSpec;
+
+    fn special_tokens(&self) -> SpecialTokens;
+
+    fn tokenizer(&self) -> &Tokenizer;
+
+    fn device(&self) -> &Device;
+
+    /// Most tokens the decoder can generate for one caption, after the start token.
+    fn max_tokens(&self) -> usize;
+
+    /// Encodes a (batch, 3, size, size) batch of preprocessed images, returning embeddings with
+    /// the same leading batch dimension.
+    fn encode(&self, images: &Tensor) -> candle_core::Result<Tensor>;
+
+    /// Runs one decoder step over the new `input_ids` of shape (1, seq_len), attending to
+    /// `image_embeds` of one image, and returns the logits of the last position. The backend keeps
+    /// whatever state it needs between the steps of one caption.
+    fn decode_step(&mut self, input_ids: &Tensor, image_embeds: &Tensor) -> candle_core::Result<Tensor>;
+
+    /// Forgets the state of the previous caption.
+    fn reset(&mut self);
+}
+
+/// Loads the weights of a registered model with its backend, returning it with the size of the
+/// weights in memory.
+pub fn load_backend(config: &ModelConfig) -> anyhow::Result<(Box<dyn CaptionBackend>, u64)> {
+    match config.backend.as_str() {
+        "blip" => {
+            let blip = Blip::load(config)?;
+            let bytes = blip.weight_bytes;
+            Ok((Box::new(blip), bytes))
+        }
+        name => anyhow::bail!("model {} has unknown backend {name:?}, expected one of {BACKENDS:?}", config.name),
+    }
+}
+
+#[derive(Clone)]
+enum BlipModel {
+    M(blip::BlipForConditionalGeneration),
+    Q(quantized_blip::BlipForConditionalGeneration),
+}
+
+/// A BLIP captioning checkpoint, from safetensors or a GGUF conversion. The architecture,
+/// preprocessing and special tokens come from the checkpoint's config files.
+#[derive(Clone)]
+pub struct Blip {
+    model: BlipModel,
+    tokenizer: Tokenizer,
+    image_spec: ImageSpec,
+    special_tokens: SpecialTokens,
+    /// What the full precision weights were loaded as; inputs are converted to it and outputs
+    /// back to f32.
+    dtype: DType,
+    weight_bytes: u64,
+    /// Positions the text decoder has embeddings for, the start token included.
+    max_positions: usize,
+    device: Device,
+}
+
+impl Blip {
+    pub fn load(config: &ModelConfig) -> anyhow::Result<Self> {
+        let model_file = config.weights_file()?;
+        let tokenizer = Tokenizer::from_file(config.config_file("tokenizer.json")?).map_err(E::msg)?;
+        let spec = BlipSpec::load(&config.config_file("config.json")?, &config.config_file("preprocessor_config.json")?)?;
+        let blip_config = &spec.config;
+
+        let device = Device::Cpu;
+        let (model, dtype, weight_bytes) = if config.quantized {
+            let vb = quantized_blip::VarBuilder::from_gguf(&model_file, &device)?;
+            spec.validate(&["text_decoder.bert"], |name| vb.get_no_shape(name).ok().map(|t| t.shape().dims().to_vec()))?;
+            // quantized blocks are kept as they are in the file
+            let weight_bytes = std::fs::metadata(&model_file)?.len();
+            (BlipModel::Q(quantized_blip::BlipForConditionalGeneration::new(blip_config, vb)?), DType::F32, weight_bytes)
+        } else {
+            let dtype = DType::from(config.dtype);
+            spec.validate(&["text_decoder.bert"], safetensors_shapes(&model_file)?)?;
+            let weight_bytes = safetensors_parameters(&model_file)? * dtype.size_in_bytes() as u64;
+            let vb =
+                unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], dtype, &device)? };
+            (BlipModel::M(blip::BlipForConditionalGeneration::new(blip_config, vb)?), dtype, weight_bytes)
+        };
+        Ok(Self {
+            model,
+            tokenizer,
+            image_spec: spec.image_spec,
+            special_tokens: spec.special_tokens,
+            dtype,
+            weight_bytes,
+            max_positions: blip_config.text_config.max_position_embeddings,
+            device,
+        })
+    }
+}
+
+impl CaptionBackend for Blip {
+    fn boxed_clone(&self) -> Box<dyn CaptionBackend> {
+        Box::new(self.clone())
+    }
+
+    fn image_spec(&self) -> &ImageSpec {
+        &self.image_spec
+    }
+
+    fn special_tokens(&self) -> SpecialTokens {
+        self.special_tokens
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.max_positions - 1
+    }
+
+    fn encode(&self, images: &Tensor) -> candle_core::Result<Tensor> {
+        let images = images.to_dtype(self.dtype)?;
+        let embeds = match &self.model {
+            BlipModel::M(m) => m.vision_model().forward(&images)?,
+            BlipModel::Q(m) => m.vision_model().forward(&images)?,
+        };
+        embeds.to_dtype(DType::F32)
+    }
+
+    fn decode_step(&mut self, input_ids: &Tensor, image_embeds: &Tensor) -> candle_core::Result<Tensor> {
+        let image_embeds = &image_embeds.to_dtype(self.dtype)?;
+        let logits = match &mut self.model {
+            BlipModel::M(m) => m.text_decoder().forward(input_ids, image_embeds)?,
+            BlipModel::Q(m) => m.text_decoder().forward(input_ids, image_embeds)?,
+        };
+        let logits = logits.squeeze(0)?;
+        logits.get(logits.dim(0)? - 1)?.to_dtype(DType::F32)
+    }
+
+    fn reset(&mut self) {
+        match &mut self.model {
+            BlipModel::M(m) => m.reset_kv_cache(),
+            BlipModel::Q(m) => m.reset_kv_cache(),
+        }
+    }
+}
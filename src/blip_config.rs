@@ -0,0 +1,358 @@
+use std::path::Path;
+use candle_core::safetensors::MmapedSafetensors;
+use candle_transformers::models::{blip, blip_text};
+use serde::Deserialize;
+use crate::backend::SpecialTokens;
+use crate::load_image::ImageSpec;
+use crate::models::Source;
+
+// Mirrors of the Hugging Face `BlipConfig` and `BlipImageProcessor` files. Checkpoints only write
+// what differs from the transformers defaults, so every field falls back to those.
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct TextConfigFile {
+    vocab_size: usize,
+    hidden_size: usize,
+    encoder_hidden_size: usize,
+    intermediate_size: usize,
+    projection_dim: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    max_position_embeddings: usize,
+    hidden_act: candle_nn::Activation,
+    layer_norm_eps: f64,
+    is_decoder: bool,
+    bos_token_id: u32,
+    sep_token_id: u32,
+}
+
+impl Default for TextConfigFile {
+    fn default() -> Self {
+        Self {
+            vocab_size: 30524,
+            hidden_size: 768,
+            encoder_hidden_size: 768,
+            intermediate_size: 3072,
+            projection_dim: 768,
+            num_hidden_layers: 12,
+            num_attention_heads: 8,
+            max_position_embeddings: 512,
+            hidden_act: candle_nn::Activation::Gelu,
+            layer_norm_eps: 1e-12,
+            is_decoder: true,
+            bos_token_id: 30522,
+            sep_token_id: 102,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct VisionConfigFile {
+    hidden_size: usize,
+    intermediate_size: usize,
+    projection_dim: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    image_size: usize,
+    patch_size: usize,
+    hidden_act: candle_nn::Activation,
+    layer_norm_eps: f64,
+}
+
+impl Default for VisionConfigFile {
+    fn default() -> Self {
+        Self {
+            hidden_size: 768,
+            intermediate_size: 3072,
+            projection_dim: 512,
+            num_hidden_layers: 12,
+            num_attention_heads: 12,
+            image_size: 384,
+            patch_size: 16,
+            hidden_act: candle_nn::Activation::Gelu,
+            layer_norm_eps: 1e-5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    text_config: TextConfigFile,
+    vision_config: VisionConfigFile,
+    projection_dim: usize,
+    image_text_hidden_size: usize,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self {
+            text_config: TextConfigFile::default(),
+            vision_config: VisionConfigFile::default(),
+            projection_dim: 512,
+            image_text_hidden_size: 256,
+        }
+    }
+}
+
+/// Older processor configs give the size as a bare number, newer ones as height and width.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ImageSize {
+    Square(u32),
+    Sized { height: u32, width: u32 },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct PreprocessorConfigFile {
+    size: ImageSize,
+    do_normalize: bool,
+    image_mean: [f32; 3],
+    image_std: [f32; 3],
+}
+
+impl Default for PreprocessorConfigFile {
+    fn default() -> Self {
+        Self {
+            size: ImageSize::Square(ImageSpec::BLIP.size),
+            do_normalize: true,
+            image_mean: ImageSpec::BLIP.mean,
+            image_std: ImageSpec::BLIP.std,
+        }
+    }
+}
+
+/// Everything about a BLIP checkpoint that used to be hard-coded for the large captioner: the
+/// architecture, the image preprocessing and the special tokens.
+#[derive(Debug, Clone)]
+pub struct BlipSpec {
+    pub config: blip::Config,
+    pub image_spec: ImageSpec,
+    pub special_tokens: SpecialTokens,
+}
+
+impl BlipSpec {
+    /// Reads a checkpoint's `config.json` and `preprocessor_config.json`.
+    pub fn load(config: &Path, preprocessor: &Path) -> anyhow::Result<Self> {
+        let file: ConfigFile = serde_json::from_slice(&std::fs::read(config)?)
+            .map_err(|e| anyhow::anyhow!("invalid model config {}: {e}", config.display()))?;
+        let processor: PreprocessorConfigFile = serde_json::from_slice(&std::fs::read(preprocessor)?)
+            .map_err(|e| anyhow::anyhow!("invalid preprocessor config {}: {e}", preprocessor.display()))?;
+
+        let size = match processor.size {
+            ImageSize::Square(size) => size,
+            ImageSize::Sized { height, width } if height == width => height,
+            ImageSize::Sized { height, width } => anyhow::bail!("only square inputs are supported, the preprocessor asks for {width}x{height}"),
+        };
+        let vision = file.vision_config;
+        if size as usize != vision.image_size {
+            anyhow::bail!("the preprocessor resizes to {size} pixels but the vision model expects {}", vision.image_size);
+        }
+        let image_spec = match processor.do_normalize {
+            true => ImageSpec { size, mean: processor.image_mean, std: processor.image_std },
+            false => ImageSpec { size, mean: [0.0; 3], std: [1.0; 3] },
+        };
+
+        let text = file.text_config;
+        let special_tokens = SpecialTokens { bos: text.bos_token_id, eos: text.sep_token_id };
+        let config = blip::Config {
+            text_config: blip_text::Config {
+                vocab_size: text.vocab_size,
+                hidden_size: text.hidden_size,
+                encoder_hidden_size: text.encoder_hidden_size,
+                intermediate_size: text.intermediate_size,
+                projection_dim: text.projection_dim,
+                num_hidden_layers: text.num_hidden_layers,
+                num_attention_heads: text.num_attention_heads,
+                max_position_embeddings: text.max_position_embeddings,
+                hidden_act: text.hidden_act,
+                layer_norm_eps: text.layer_norm_eps,
+                is_decoder: text.is_decoder,
+            },
+            vision_config: blip::VisionConfig {
+                hidden_size: vision.hidden_size,
+                intermediate_size: vision.intermediate_size,
+                projection_dim: vision.projection_dim,
+                num_hidden_layers: vision.num_hidden_layers,
+                num_attention_heads: vision.num_attention_heads,
+                image_size: vision.image_size,
+                patch_size: vision.patch_size,
+                hidden_act: vision.hidden_act,
+                layer_norm_eps: vision.layer_norm_eps,
+            },
+            projection_dim: file.projection_dim,
+            image_text_hidden_size: file.image_text_hidden_size,
+        };
+        Ok(Self { config, image_spec, special_tokens })
+    }
+
+    /// Fetches, if needed, and reads the config files of a model source.
+    pub fn from_source(source: &Source) -> anyhow::Result<Self> {
+        Self::load(&source.get("config.json")?, &source.get("preprocessor_config.json")?)
+    }
+
+    /// Checks the config against the shapes of the loaded tensors, so a config that does not
+    /// belong to the weights fails with a readable message instead of deep inside the model.
+    /// `text_models` are the prefixes of the BERT text models in the checkpoint, e.g.
+    /// `text_decoder.bert` for the captioner. `shape_of` looks up a tensor's shape by name.
+    pub fn validate(&self, text_models: &[&str], shape_of: impl Fn(&str) -> Option<Vec<usize>>) -> anyhow::Result<()> {
+        let expect = |name: String, expected: Vec<usize>| match shape_of(&name) {
+            Some(actual) if actual == expected => Ok(()),
+            Some(actual) => Err(anyhow::anyhow!("{name} has shape {actual:?} in the checkpoint but the config implies {expected:?}")),
+            None => Err(anyhow::anyhow!("the checkpoint has no {name}")),
+        };
+        let vision = &self.config.vision_config;
+        let text = &self.config.text_config;
+        // configs that would panic here or while building the model, with zero layers, heads or
+        // patch size, get a readable error first
+        for (tower, hidden, heads) in [("text", text.hidden_size, text.num_attention_heads), ("vision", vision.hidden_size, vision.num_attention_heads)] {
+            if heads == 0 || hidden % heads != 0 {
+                anyhow::bail!("the {tower} hidden size {hidden} does not split into {heads} attention heads");
+            }
+        }
+        if vision.patch_size == 0 {
+            anyhow::bail!("the vision patch size must be positive");
+        }
+        let last_layer = text.num_hidden_layers.checked_sub(1).ok_or_else(|| anyhow::anyhow!("the text config has no hidden layers"))?;
+
+        let patches = (vision.image_size / vision.patch_size).pow(2);
+        expect("vision_model.embeddings.position_embedding".to_string(), vec![1, patches + 1, vision.hidden_size])?;
+
+        for prefix in text_models {
+            expect(format!("{prefix}.embeddings.word_embeddings.weight"), vec![text.vocab_size, text.hidden_size])?;
+            expect(format!("{prefix}.embeddings.position_embeddings.weight"), vec![text.max_position_embeddings, text.hidden_size])?;
+            expect(
+                format!("{prefix}.encoder.layer.0.crossattention.self.key.weight"),
+                vec![text.hidden_size, text.encoder_hidden_size],
+            )?;
+            expect(
+                format!("{prefix}.encoder.layer.{last_layer}.output.dense.weight"),
+                vec![text.hidden_size, text.intermediate_size],
+            )?;
+        }
+        for (name, id) in [("bos", self.special_tokens.bos), ("sep", self.special_tokens.eos)] {
+            if id as usize >= text.vocab_size {
+                anyhow::bail!("the {name} token {id} is outside the vocabulary of {} tokens", text.vocab_size);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of parameters in a safetensors checkpoint.
+pub fn safetensors_parameters(path: &Path) -> anyhow::Result<u64> {
+    let tensors = unsafe { MmapedSafetensors::new(path)? };
+    Ok(tensors.tensors().iter().map(|(_, view)| view.shape().iter().product::<usize>() as u64).sum())
+}
+
+/// Tensor shapes of a safetensors checkpoint, for [`BlipSpec::validate`].
+pub fn safetensors_shapes(path: &Path) -> anyhow::Result<impl Fn(&str) -> Option<Vec<usize>>> {
+    // only the header is read, the mapping is dropped with the closure
+    let tensors = unsafe { MmapedSafetensors::new(path)? };
+    Ok(move |name: &str| tensors.get(name).ok().map(|view| view.shape().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Writes the two config files and reads them back.
+    fn spec(config: serde_json::Value, preprocessor: serde_json::Value) -> anyhow::Result<BlipSpec> {
+        let dir = tempfile::tempdir()?;
+        let (config_path, preprocessor_path) = (dir.path().join("config.json"), dir.path().join("preprocessor_config.json"));
+        std::fs::write(&config_path, config.to_string())?;
+        std::fs::write(&preprocessor_path, preprocessor.to_string())?;
+        BlipSpec::load(&config_path, &preprocessor_path)
+    }
+
+    fn default_spec() -> BlipSpec {
+        spec(serde_json::json!({}), serde_json::json!({})).unwrap()
+    }
+
+    /// The tensor shapes of a captioning checkpoint with the default config.
+    fn default_shapes() -> HashMap<String, Vec<usize>> {
+        [
+            ("vision_model.embeddings.position_embedding", vec![1, 577, 768]),
+            ("text_decoder.bert.embeddings.word_embeddings.weight", vec![30524, 768]),
+            ("text_decoder.bert.embeddings.position_embeddings.weight", vec![512, 768]),
+            ("text_decoder.bert.encoder.layer.0.crossattention.self.key.weight", vec![768, 768]),
+            ("text_decoder.bert.encoder.layer.11.output.dense.weight", vec![768, 3072]),
+        ]
+        .into_iter()
+        .map(|(name, shape)| (name.to_string(), shape))
+        .collect()
+    }
+
+    fn validate(spec: &BlipSpec, shapes: &HashMap<String, Vec<usize>>) -> anyhow::Result<()> {
+        spec.validate(&["text_decoder.bert"], |name| shapes.get(name).cloned())
+    }
+
+    #[test]
+    fn missing_fields_fall_back_to_the_transformers_defaults() {
+        let spec = default_spec();
+        assert_eq!(spec.config.vision_config.image_size, 384);
+        assert_eq!(spec.config.text_config.vocab_size, 30524);
+        assert_eq!((spec.special_tokens.bos, spec.special_tokens.eos), (30522, 102));
+        assert_eq!(spec.image_spec.size, 384);
+        assert_eq!(spec.image_spec.mean, ImageSpec::BLIP.mean);
+    }
+
+    #[test]
+    fn reads_both_forms_of_the_processor_size() {
+        let config = serde_json::json!({ "vision_config": { "image_size": 224 } });
+        assert_eq!(spec(config.clone(), serde_json::json!({ "size": 224 })).unwrap().image_spec.size, 224);
+        let sized = serde_json::json!({ "size": { "height": 224, "width": 224 } });
+        assert_eq!(spec(config.clone(), sized).unwrap().image_spec.size, 224);
+        let wide = serde_json::json!({ "size": { "height": 224, "width": 448 } });
+        assert!(spec(config, wide).is_err());
+    }
+
+    #[test]
+    fn processor_size_must_match_the_vision_model() {
+        assert!(spec(serde_json::json!({}), serde_json::json!({ "size": 224 })).is_err());
+    }
+
+    #[test]
+    fn normalization_can_be_turned_off() {
+        let preprocessor = serde_json::json!({ "do_normalize": false, "image_mean": [0.5, 0.5, 0.5] });
+        let spec = spec(serde_json::json!({}), preprocessor).unwrap();
+        assert_eq!((spec.image_spec.mean, spec.image_spec.std), ([0.0; 3], [1.0; 3]));
+    }
+
+    #[test]
+    fn special_tokens_come_from_the_text_config() {
+        let config = serde_json::json!({ "text_config": { "bos_token_id": 101, "sep_token_id": 103 } });
+        let spec = spec(config, serde_json::json!({})).unwrap();
+        assert_eq!((spec.special_tokens.bos, spec.special_tokens.eos), (101, 103));
+    }
+
+    #[test]
+    fn validate_accepts_matching_weights() {
+        validate(&default_spec(), &default_shapes()).unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_mismatched_and_missing_tensors() {
+        let mut shapes = default_shapes();
+        shapes.insert("text_decoder.bert.embeddings.word_embeddings.weight".to_string(), vec![30522, 768]);
+        assert!(validate(&default_spec(), &shapes).is_err());
+        let mut shapes = default_shapes();
+        shapes.remove("vision_model.embeddings.position_embedding");
+        assert!(validate(&default_spec(), &shapes).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_configs_that_would_panic() {
+        let broken = |text_config: serde_json::Value| spec(serde_json::json!({ "text_config": text_config }), serde_json::json!({})).unwrap();
+        assert!(validate(&broken(serde_json::json!({ "num_hidden_layers": 0 })), &default_shapes()).is_err());
+        assert!(validate(&broken(serde_json::json!({ "num_attention_heads": 0 })), &default_shapes()).is_err());
+        assert!(validate(&broken(serde_json::json!({ "num_attention_heads": 7 })), &default_shapes()).is_err());
+        assert!(validate(&broken(serde_json::json!({ "bos_token_id": 40000 })), &default_shapes()).is_err());
+        let no_patches = spec(serde_json::json!({ "vision_config": { "patch_size": 0 } }), serde_json::json!({})).unwrap();
+        assert!(validate(&no_patches, &default_shapes()).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::{linear, Linear, VarBuilder};
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+use crate::blip_config::{safetensors_shapes, BlipSpec};
+use crate::blip_text_encoder::TextEncoder;
+use crate::blip_vision::VisionModel;
+use crate::caption_cache::image_hash;
+use crate::load_image::{decode_image, image_to_tensor, ImageLimits, ImageSpec, PreprocessOptions};
+use crate::models::{self, LoadOnce};
+use anyhow::Error as E;
+
+/// Most candidate texts scored against one image in a single request.
+pub const MAX_MATCH_TEXTS: usize = 32;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MatchOptions {
+    /// Candidate texts, e.g. alt texts, to score against the image.
+    pub texts: Vec<String>,
+    #[serde(flatten)]
+    pub preprocess: PreprocessOptions,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchResult {
+    pub image_id: String,
+    /// One score per candidate, in the order they were sent.
+    pub scores: Vec<MatchScore>,
+}
+
+/// How well one candidate text describes the image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchScore {
+    pub text: String,
+    /// Probability from the image-text matching head that the text matches the image.
+    pub match_probability: f32,
+    /// Cosine similarity of the contrastive (ITC) image and text projections.
+    pub similarity: f32,
+}
+
+fn normalize(xs: &Tensor) -> candle_core::Result<Tensor> {
+    xs.broadcast_div(&xs.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?)
+}
+
+/// BLIP image-text retrieval model: the vision tower, a text encoder that can cross-attend to the
+/// image, the binary matching head and the contrastive projections.
+pub struct ImageTextMatcher {
+    vision_model: VisionModel,
+    text_encoder: TextEncoder,
+    vision_proj: Linear,
+    text_proj: Linear,
+    itm_head: Linear,
+    tokenizer: Tokenizer,
+    image_spec: ImageSpec,
+    max_tokens: usize,
+    device: Device,
+}
+
+static MATCHER: LoadOnce<ImageTextMatcher> = LoadOnce::new();
+
+impl ImageTextMatcher {
+    /// The matcher shared by every request, loaded by the first one that needs it. Scoring only
+    /// reads the weights, so requests use it side by side.
+    pub fn shared() -> anyhow::Result<&'static Self> {
+        MATCHER.get_or_load(Self::load)
+    }
+
+    fn load() -> anyhow::Result<Self> {
+        let source = models::registry().matcher();
+        let model_file = source.get("model.safetensors")?;
+        let tokenizer = Tokenizer::from_file(source.get("tokenizer.json")?).map_err(E::msg)?;
+        let spec = BlipSpec::from_source(source)?;
+        spec.validate(&["text_encoder"], safetensors_shapes(&model_file)?)?;
+
+        let config = &spec.config;
+        let text_config = &config.text_config;
+        let device = Device::Cpu;
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], DType::F32, &device)? };
+        Ok(Self {
+            vision_model: VisionModel::new(&config.vision_config, vb.pp("vision_model"))?,
+            text_encoder: TextEncoder::new(text_config, true, vb.pp("text_encoder"))?,
+            vision_proj: linear(config.vision_config.hidden_size, config.image_text_hidden_size, vb.pp("vision_proj"))?,
+            text_proj: linear(text_config.hidden_size, config.image_text_hidden_size, vb.pp("text_proj"))?,
+            itm_head: linear(text_config.hidden_size, 2, vb.pp("itm_head"))?,
+            tokenizer,
+            image_spec: spec.image_spec,
+            max_tokens: text_config.max_position_embeddings,
+            device,
+        })
+    }
+
+    /// How images must be preprocessed for [`Self::encode_image`].
+    pub fn image_spec(&self) -> &ImageSpec {
+        &self.image_spec
+    }
+
+    /// Runs the vision tower over a preprocessed (3, size, size) image.
+    pub fn encode_image(&self, image: &Tensor) -> anyhow::Result<Tensor> {
+        Ok(image.unsqueeze(0)?.to_device(&self.device)?.apply(&self.vision_model)?)
+    }
+
+    fn tokenize(&self, text: &str) -> anyhow::Result<Tensor> {
+        let mut ids = self.tokenizer.encode(text, true).map_err(E::msg)?.get_ids().to_vec();
+        ids.truncate(self.max_tokens);
+        Ok(Tensor::new(ids.as_slice(), &self.device)?.unsqueeze(0)?)
+    }
+
+    /// Scores every text against the image embeddings from [`Self::encode_image`], in order.
+    pub fn score(&self, image_embeds: &Tensor, texts: &[String]) -> anyhow::Result<Vec<MatchScore>> {
+        let image_feat = normalize(&image_embeds.narrow(1, 0, 1)?.squeeze(1)?.apply(&self.vision_proj)?)?;
+        texts
+            .iter()
+            .map(|text| {
+                let input_ids = self.tokenize(text)?;
+                let fused = self.text_encoder.forward(&input_ids, Some(image_embeds))?;
+                let itm_logits = fused.narrow(1, 0, 1)?.squeeze(1)?.apply(&self.itm_head)?;
+                let match_probability = candle_nn::ops::softmax(&itm_logits, D::Minus1)?.squeeze(0)?.get(1)?.to_scalar()?;
+
+                let text_only = self.text_encoder.forward(&input_ids, None)?;
+                let text_feat = normalize(&text_only.narrow(1, 0, 1)?.squeeze(1)?.apply(&self.text_proj)?)?;
+                let similarity = (&image_feat * &text_feat)?.sum_all()?.to_scalar()?;
+                Ok(MatchScore { text: text.clone(), match_probability, similarity })
+            })
+            .collect()
+    }
+}
+
+/// Scores every candidate text in `options` against `image`.
+pub fn match_texts(image: &[u8], options: &MatchOptions, limits: &ImageLimits) -> anyhow::Result<MatchResult> {
+    let (img, info) = decode_image(image, &options.preprocess, limits)?;
+    println!("matching {} texts against image {info:?}", options.texts.len());
+    let matcher = ImageTextMatcher::shared()?;
+    let image_embeds = matcher.encode_image(&image_to_tensor(&img, &options.preprocess, matcher.image_spec())?)?;
+    let scores = matcher.score(&image_embeds, &options.texts)?;
+    Ok(MatchResult { image_id: image_hash(image), scores })
+}
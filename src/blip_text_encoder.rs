@@ -0,0 +1,136 @@
+use candle_core::{Result, Tensor, D};
+use candle_nn::{embedding, layer_norm, linear, Embedding, LayerNorm, Linear, VarBuilder};
+use candle_transformers::models::blip_text::Config;
+
+// candle's BLIP text model is the causal caption decoder and always cross-attends. Image-text
+// matching and question encoding need the bidirectional BERT encoder instead, optionally attending
+// to image embeddings, so that is built here with the same weight layout.
+
+struct SelfAttention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    num_heads: usize,
+    head_size: usize,
+}
+
+impl SelfAttention {
+    fn new(cfg: &Config, context_size: usize, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            query: linear(cfg.hidden_size, cfg.hidden_size, vb.pp("query"))?,
+            key: linear(context_size, cfg.hidden_size, vb.pp("key"))?,
+            value: linear(context_size, cfg.hidden_size, vb.pp("value"))?,
+            num_heads: cfg.num_attention_heads,
+            head_size: cfg.hidden_size / cfg.num_attention_heads,
+        })
+    }
+
+    fn split_heads(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, _) = xs.dims3()?;
+        xs.reshape((b_size, seq_len, self.num_heads, self.head_size))?.permute((0, 2, 1, 3))?.contiguous()
+    }
+
+    /// Attends from `xs` to `context`, or to `xs` itself when there is no context.
+    fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Result<Tensor> {
+        let context = context.unwrap_or(xs);
+        let query = self.split_heads(&xs.apply(&self.query)?)?;
+        let key = self.split_heads(&context.apply(&self.key)?)?;
+        let value = self.split_heads(&context.apply(&self.value)?)?;
+        let scores = (query.matmul(&key.t()?)? / (self.head_size as f64).sqrt())?;
+        candle_nn::ops::softmax_last_dim(&scores)?
+            .matmul(&value)?
+            .permute((0, 2, 1, 3))?
+            .flatten_from(D::Minus2)
+    }
+}
+
+struct Attention {
+    self_: SelfAttention,
+    dense: Linear,
+    layer_norm: LayerNorm,
+}
+
+impl Attention {
+    fn new(cfg: &Config, context_size: usize, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_: SelfAttention::new(cfg, context_size, vb.pp("self"))?,
+            dense: linear(cfg.hidden_size, cfg.hidden_size, vb.pp("output.dense"))?,
+            layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("output.LayerNorm"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Result<Tensor> {
+        (self.self_.forward(xs, context)?.apply(&self.dense)? + xs)?.apply(&self.layer_norm)
+    }
+}
+
+struct Layer {
+    attention: Attention,
+    cross_attention: Option<Attention>,
+    intermediate: Linear,
+    activation: candle_nn::Activation,
+    output: Linear,
+    layer_norm: LayerNorm,
+}
+
+impl Layer {
+    fn new(cfg: &Config, cross_attention: bool, vb: VarBuilder) -> Result<Self> {
+        let cross_attention = match cross_attention {
+            true => Some(Attention::new(cfg, cfg.encoder_hidden_size, vb.pp("crossattention"))?),
+            false => None,
+        };
+        Ok(Self {
+            attention: Attention::new(cfg, cfg.hidden_size, vb.pp("attention"))?,
+            cross_attention,
+            intermediate: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("intermediate.dense"))?,
+            activation: cfg.hidden_act,
+            output: linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("output.dense"))?,
+            layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("output.LayerNorm"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Result<Tensor> {
+        let mut xs = self.attention.forward(xs, None)?;
+        if let (Some(cross_attention), Some(context)) = (&self.cross_attention, context) {
+            xs = cross_attention.forward(&xs, Some(context))?;
+        }
+        let intermediate = xs.apply(&self.intermediate)?.apply(&self.activation)?;
+        (intermediate.apply(&self.output)? + xs)?.apply(&self.layer_norm)
+    }
+}
+
+/// Bidirectional BLIP text encoder. Built with cross-attention it can condition the text on
+/// image (or other) embeddings; without a context it is a plain text encoder.
+pub struct TextEncoder {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    layer_norm: LayerNorm,
+    layers: Vec<Layer>,
+}
+
+impl TextEncoder {
+    pub fn new(cfg: &Config, cross_attention: bool, vb: VarBuilder) -> Result<Self> {
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| Layer::new(cfg, cross_attention, vb.pp("encoder.layer").pp(i)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            word_embeddings: embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("embeddings.word_embeddings"))?,
+            position_embeddings: embedding(cfg.max_position_embeddings, cfg.hidden_size, vb.pp("embeddings.position_embeddings"))?,
+            layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("embeddings.LayerNorm"))?,
+            layers,
+        })
+    }
+
+    /// Encodes `input_ids` of shape (1, seq_len), cross-attending to `context` when given, and
+    /// returns the last hidden state of shape (1, seq_len, hidden).
+    pub fn forward(&self, input_ids: &Tensor, context: Option<&Tensor>) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let positions = Tensor::arange(0, seq_len as u32, input_ids.device())?.unsqueeze(0)?;
+        let embeddings = (input_ids.apply(&self.word_embeddings)? + positions.apply(&self.position_embeddings)?)?;
+        let mut xs = embeddings.apply(&self.layer_norm)?;
+        for layer in &self.layers {
+            xs = layer.forward(&xs, context)?;
+        }
+        Ok(xs)
+    }
+}
@@ -0,0 +1,133 @@
+use candle_core::{Module, Result, Tensor, D};
+use candle_nn::{conv2d, layer_norm, linear, Conv2d, Conv2dConfig, LayerNorm, Linear, VarBuilder};
+use candle_transformers::models::blip::VisionConfig;
+
+// candle only builds its BLIP vision model as part of `BlipForConditionalGeneration`, which needs
+// the captioning text decoder weights. The ITM and VQA checkpoints ship the same vision tower
+// next to other heads, so it is rebuilt here from the same layers.
+
+struct VisionEmbeddings {
+    class_embedding: Tensor,
+    patch_embedding: Conv2d,
+    position_embedding: Tensor,
+}
+
+impl VisionEmbeddings {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let class_embedding = vb.get((1, 1, cfg.hidden_size), "class_embedding")?;
+        let conv_cfg = Conv2dConfig { stride: cfg.patch_size, ..Default::default() };
+        let patch_embedding = conv2d(3, cfg.hidden_size, cfg.patch_size, conv_cfg, vb.pp("patch_embedding"))?;
+        let num_positions = (cfg.image_size / cfg.patch_size).pow(2) + 1;
+        let position_embedding = vb.get((1, num_positions, cfg.hidden_size), "position_embedding")?;
+        Ok(Self { class_embedding, patch_embedding, position_embedding })
+    }
+}
+
+impl Module for VisionEmbeddings {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let b_size = xs.dim(0)?;
+        let patch_embeds = xs.apply(&self.patch_embedding)?.flatten_from(2)?.t()?;
+        let d = self.class_embedding.dim(D::Minus1)?;
+        let class_embeds = self.class_embedding.broadcast_as((b_size, 1, d))?.to_dtype(xs.dtype())?;
+        let embeddings = Tensor::cat(&[&class_embeds, &patch_embeds], 1)?;
+        let position_embedding = self.position_embedding.narrow(1, 0, embeddings.dim(1)?)?;
+        embeddings.broadcast_add(&position_embedding)
+    }
+}
+
+struct Attention {
+    qkv: Linear,
+    projection: Linear,
+    scale: f64,
+    num_heads: usize,
+}
+
+impl Attention {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let embed_dim = cfg.hidden_size;
+        let head_dim = embed_dim / cfg.num_attention_heads;
+        Ok(Self {
+            qkv: linear(embed_dim, 3 * embed_dim, vb.pp("qkv"))?,
+            projection: linear(embed_dim, embed_dim, vb.pp("projection"))?,
+            scale: 1f64 / (head_dim as f64).sqrt(),
+            num_heads: cfg.num_attention_heads,
+        })
+    }
+}
+
+impl Module for Attention {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_sz, tgt_len, embed_dim) = xs.dims3()?;
+        let mixed_qkv = xs
+            .apply(&self.qkv)?
+            .reshape((b_sz, tgt_len, 3, self.num_heads, embed_dim / self.num_heads))?
+            .permute((2, 0, 3, 1, 4))?;
+        let (query, key, value) = (mixed_qkv.get(0)?, mixed_qkv.get(1)?, mixed_qkv.get(2)?);
+        let attention_scores = (query.matmul(&key.t()?)? * self.scale)?;
+        candle_nn::ops::softmax_last_dim(&attention_scores)?
+            .matmul(&value)?
+            .permute((0, 2, 1, 3))?
+            .flatten_from(D::Minus2)?
+            .apply(&self.projection)
+    }
+}
+
+struct EncoderLayer {
+    self_attn: Attention,
+    layer_norm1: LayerNorm,
+    fc1: Linear,
+    fc2: Linear,
+    activation: candle_nn::Activation,
+    layer_norm2: LayerNorm,
+}
+
+impl EncoderLayer {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: Attention::new(cfg, vb.pp("self_attn"))?,
+            layer_norm1: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layer_norm1"))?,
+            fc1: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("mlp.fc1"))?,
+            fc2: linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("mlp.fc2"))?,
+            activation: cfg.hidden_act,
+            layer_norm2: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layer_norm2"))?,
+        })
+    }
+}
+
+impl Module for EncoderLayer {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = (xs.apply(&self.layer_norm1)?.apply(&self.self_attn)? + xs)?;
+        let mlp = xs.apply(&self.layer_norm2)?.apply(&self.fc1)?.apply(&self.activation)?.apply(&self.fc2)?;
+        mlp + xs
+    }
+}
+
+/// The BLIP ViT image encoder, returning the last hidden state after the final layer norm.
+pub struct VisionModel {
+    embeddings: VisionEmbeddings,
+    layers: Vec<EncoderLayer>,
+    post_layernorm: LayerNorm,
+}
+
+impl VisionModel {
+    pub fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| EncoderLayer::new(cfg, vb.pp("encoder.layers").pp(i)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            embeddings: VisionEmbeddings::new(cfg, vb.pp("embeddings"))?,
+            layers,
+            post_layernorm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("post_layernorm"))?,
+        })
+    }
+}
+
+impl Module for VisionModel {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.apply(&self.embeddings)?;
+        for layer in &self.layers {
+            xs = xs.apply(layer)?;
+        }
+        xs.apply(&self.post_layernorm)
+    }
+}
@@ -0,0 +1,181 @@
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::models::blip_text;
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+use crate::backend::SpecialTokens;
+use crate::blip_config::{safetensors_shapes, BlipSpec};
+use crate::blip_text_encoder::TextEncoder;
+use crate::blip_vision::VisionModel;
+use crate::caption_cache::image_hash;
+use crate::load_image::{decode_image, image_to_tensor, ImageLimits, ImageSpec, PreprocessError, PreprocessOptions};
+use crate::models::{self, LoadOnce};
+use crate::token_output_stream::TokenOutputStream;
+use anyhow::Error as E;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VqaOptions {
+    pub question: String,
+    /// Upper bound on the answer length; answers are usually a few words. May not exceed what the
+    /// answer decoder has positions for.
+    pub max_tokens: usize,
+    #[serde(flatten)]
+    pub preprocess: PreprocessOptions,
+}
+
+impl Default for VqaOptions {
+    fn default() -> Self {
+        Self {
+            question: String::new(),
+            max_tokens: 20,
+            preprocess: PreprocessOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VqaResult {
+    pub image_id: String,
+    pub question: String,
+    pub answer: String,
+}
+
+/// BLIP visual question answering: the question is encoded while cross-attending to the image,
+/// then the answer decoder generates from the question embeddings alone.
+pub struct QuestionAnswerer {
+    vision_model: VisionModel,
+    question_encoder: TextEncoder,
+    /// Cloned for every answer, which shares the weights but gives it its own KV cache.
+    answer_decoder: blip_text::TextLMHeadModel,
+    tokenizer: Tokenizer,
+    image_spec: ImageSpec,
+    special_tokens: SpecialTokens,
+    /// Positions the text models have embeddings for; questions are truncated to it and answers
+    /// may not outgrow it.
+    max_positions: usize,
+    device: Device,
+}
+
+static ANSWERER: LoadOnce<QuestionAnswerer> = LoadOnce::new();
+
+impl QuestionAnswerer {
+    /// The model shared by every request, loaded by the first one that needs it.
+    pub fn shared() -> anyhow::Result<&'static Self> {
+        ANSWERER.get_or_load(Self::load)
+    }
+
+    fn load() -> anyhow::Result<Self> {
+        let source = models::registry().answerer();
+        let model_file = source.get("model.safetensors")?;
+        let tokenizer = Tokenizer::from_file(source.get("tokenizer.json")?).map_err(E::msg)?;
+        let spec = BlipSpec::from_source(source)?;
+        // one text config describes both, the decoder cross-attends to the question embeddings
+        spec.validate(&["text_encoder", "text_decoder.bert"], safetensors_shapes(&model_file)?)?;
+
+        let config = &spec.config;
+        let text_config = &config.text_config;
+        let device = Device::Cpu;
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], DType::F32, &device)? };
+        Ok(Self {
+            vision_model: VisionModel::new(&config.vision_config, vb.pp("vision_model"))?,
+            question_encoder: TextEncoder::new(text_config, true, vb.pp("text_encoder"))?,
+            answer_decoder: blip_text::TextLMHeadModel::new(text_config, vb.pp("text_decoder"))?,
+            tokenizer,
+            image_spec: spec.image_spec,
+            special_tokens: spec.special_tokens,
+            max_positions: text_config.max_position_embeddings,
+            device,
+        })
+    }
+
+    /// How images must be preprocessed for [`Self::answer`].
+    pub fn image_spec(&self) -> &ImageSpec {
+        &self.image_spec
+    }
+
+    /// Answers `question` about a preprocessed (3, size, size) image, greedily decoding at most
+    /// `max_tokens` tokens and handing every streamed piece of text to `on_token`.
+    pub fn answer(
+        &self,
+        image: &Tensor,
+        question: &str,
+        max_tokens: usize,
+        mut on_token: impl FnMut(String) -> anyhow::Result<()>,
+    ) -> anyhow::Result<String> {
+        let image_embeds = image.unsqueeze(0)?.to_device(&self.device)?.apply(&self.vision_model)?;
+        let mut question_ids = self.tokenizer.encode(question, true).map_err(E::msg)?.get_ids().to_vec();
+        question_ids.truncate(self.max_positions);
+        let question_ids = Tensor::new(question_ids.as_slice(), &self.device)?.unsqueeze(0)?;
+        let question_embeds = self.question_encoder.forward(&question_ids, Some(&image_embeds))?;
+
+        let mut answer_decoder = self.answer_decoder.clone();
+        let mut tokenizer = TokenOutputStream::new(self.tokenizer.clone());
+        // no temperature means argmax, so the seed is never used
+        let mut logits_processor = LogitsProcessor::new(0, None, None);
+        let mut token_ids = vec![self.special_tokens.bos];
+        let mut answer = String::new();
+        for index in 0..max_tokens {
+            let context_size = if index > 0 { 1 } else { token_ids.len() };
+            let start_pos = token_ids.len().saturating_sub(context_size);
+            let input_ids = Tensor::new(&token_ids[start_pos..], &self.device)?.unsqueeze(0)?;
+            let logits = answer_decoder.forward(&input_ids, &question_embeds)?.squeeze(0)?;
+            let token = logits_processor.sample(&logits.get(logits.dim(0)? - 1)?)?;
+            if token == self.special_tokens.eos {
+                break;
+            }
+            token_ids.push(token);
+            if let Some(t) = tokenizer.next_token(token)? {
+                answer += &t;
+                on_token(t)?;
+            }
+        }
+        if let Some(rest) = tokenizer.decode_rest().map_err(E::msg)? {
+            answer += &rest;
+            on_token(rest)?;
+        }
+        Ok(answer)
+    }
+}
+
+/// Rejects a `max_tokens` longer than an answer decoder with `max_positions` positions can
+/// generate after its start token.
+fn check_max_tokens(max_positions: usize, max_tokens: usize) -> Result<(), PreprocessError> {
+    let limit = max_positions - 1;
+    if max_tokens > limit {
+        return Err(PreprocessError::InvalidRequest(format!("max_tokens must be at most {limit}, got {max_tokens}")));
+    }
+    Ok(())
+}
+
+/// Answers `options.question` about `image`, streaming the answer to
+/// `on_token`. Blocks for the whole generation.
+pub fn answer_question(
+    image: &[u8],
+    options: &VqaOptions,
+    limits: &ImageLimits,
+    on_token: impl FnMut(String) -> anyhow::Result<()>,
+) -> anyhow::Result<VqaResult> {
+    let answerer = QuestionAnswerer::shared()?;
+    check_max_tokens(answerer.max_positions, options.max_tokens)?;
+    let (img, info) = decode_image(image, &options.preprocess, limits)?;
+    println!("answering {:?} about image {info:?}", options.question);
+    let tensor = image_to_tensor(&img, &options.preprocess, answerer.image_spec())?;
+    let answer = answerer.answer(&tensor, &options.question, options.max_tokens, on_token)?;
+    Ok(VqaResult { image_id: image_hash(image), question: options.question.clone(), answer })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_tokens_leaves_room_for_the_start_token() {
+        assert!(check_max_tokens(512, 1).is_ok());
+        assert!(check_max_tokens(512, 511).is_ok());
+        let error = check_max_tokens(512, 512).unwrap_err();
+        assert!(matches!(error, PreprocessError::InvalidRequest(_)));
+        assert_eq!(error.to_string(), "invalid request: max_tokens must be at most 511, got 512");
+    }
+}
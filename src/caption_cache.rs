@@ -0,0 +1,358 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::lru::Lru;
+use crate::models::{self, ModelConfig};
+use crate::options::{CaptionOptions, CaptionResult};
+
+/// Hex SHA-256 of the raw upload. This is the `image_id` of an image, both in the cache keys and
+/// for requests that reference an earlier upload.
+pub fn image_hash(image: &[u8]) -> String {
+    hex(&Sha256::digest(image))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Key of a caption: the image id, the model the request resolved to and every option that can
+/// change the result, i.e. the sampling settings and the preprocessing.
+///
+/// The model goes in with its whole registry entry rather than the request's `model` and
+/// `quantized` options, so requests that reach the same model share entries, and cached captions
+/// stop matching once a name is pointed at another checkpoint.
+pub fn cache_key(image_id: &str, model: &ModelConfig, options: &CaptionOptions) -> String {
+    let mut options = options.clone();
+    // where a caption is published or indexed, or how the image was referenced, does not change it
+    options.channel = None;
+    options.image_id = None;
+    options.index = false;
+    // covered by `model`
+    options.model = None;
+    options.quantized = false;
+    let model = serde_json::to_vec(model).expect("model configs always serialize");
+    let mut hasher = Sha256::new();
+    hasher.update(image_id.as_bytes());
+    // length-prefixed so the model cannot run into the options after it
+    hasher.update((model.len() as u64).to_le_bytes());
+    hasher.update(model);
+    hasher.update(serde_json::to_vec(&options).expect("options always serialize"));
+    hex(&hasher.finalize())
+}
+
+/// Optional on-disk tier: one JSON file per caption, trimmed least recently used first once the
+/// directory grows past `max_bytes`. The directory is only listed when the cache is opened, from
+/// then on the size and last use of every file are tracked in memory.
+struct DiskTier {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Every cache file by key, weighted by its size in bytes.
+    files: Mutex<Lru<()>>,
+}
+
+impl DiskTier {
+    fn open(dir: &Path, max_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let tier = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            files: Mutex::new(Lru::new(usize::try_from(max_bytes).unwrap_or(usize::MAX))),
+        };
+        // oldest first, so the recency order carries over from the last run
+        for (_, size, path) in tier.list()? {
+            let Some(key) = path.file_stem().and_then(|stem| stem.to_str()) else { continue };
+            tier.track(key, size as usize)?;
+        }
+        Ok(tier)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// All cache files in the directory with their size and last use, oldest first.
+    fn list(&self) -> std::io::Result<Vec<(SystemTime, u64, PathBuf)>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let meta = std::fs::metadata(&path)?;
+                files.push((meta.modified()?, meta.len(), path));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn get(&self, key: &str) -> Option<CaptionResult> {
+        let path = self.path(key);
+        let result = serde_json::from_slice(&std::fs::read(&path).ok()?).ok()?;
+        // a hit counts as a use, so eviction drops the least recently used files first; the
+        // modification time keeps that order for the next run
+        self.files.lock().unwrap().get(key);
+        let _ = std::fs::File::options().write(true).open(&path).and_then(|f| f.set_modified(SystemTime::now()));
+        Some(result)
+    }
+
+    fn insert(&self, key: &str, result: &CaptionResult) -> std::io::Result<()> {
+        let data = serde_json::to_vec(result)?;
+        let tmp = self.dir.join(format!("{key}.tmp"));
+        std::fs::write(&tmp, &data)?;
+        std::fs::rename(&tmp, self.path(key))?;
+        self.track(key, data.len())
+    }
+
+    /// Records the file of `key` as the most recently used and deletes the files that no longer
+    /// fit next to it.
+    fn track(&self, key: &str, size: usize) -> std::io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        for evicted in files.insert(key, (), size) {
+            std::fs::remove_file(self.path(&evicted))?;
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> std::io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        for (_, _, path) in self.list()? {
+            std::fs::remove_file(path)?;
+        }
+        files.clear();
+        Ok(())
+    }
+
+    /// Number of files and their summed size.
+    fn usage(&self) -> (usize, u64) {
+        let files = self.files.lock().unwrap();
+        (files.len(), files.used() as u64)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub memory_entries: usize,
+    pub memory_capacity: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_entries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_max_bytes: Option<u64>,
+}
+
+/// Content-addressed cache of finished captions, so re-uploads of the same image with the same
+/// options are answered without running the model. Disk errors are logged and treated as misses,
+/// a broken cache never fails a caption.
+pub struct CaptionCache {
+    /// In-memory tier, every caption weighs one so the budget is an entry count.
+    memory: Mutex<Lru<CaptionResult>>,
+    disk: Option<DiskTier>,
+}
+
+impl CaptionCache {
+    /// A cache keeping `capacity` captions in memory, 0 disables the memory tier. With `dir` set,
+    /// captions are also written there and survive restarts, up to `max_disk_bytes`.
+    pub fn new(capacity: usize, dir: Option<&Path>, max_disk_bytes: u64) -> anyhow::Result<Self> {
+        let disk = dir.map(|dir| DiskTier::open(dir, max_disk_bytes)).transpose()?;
+        Ok(Self { memory: Mutex::new(Lru::new(capacity)), disk })
+    }
+
+    /// Looks `key` up in memory, then on disk, promoting disk hits into memory. Hits come back
+    /// with `cached` set.
+    pub fn get(&self, key: &str) -> Option<CaptionResult> {
+        let hit = self.memory.lock().unwrap().get(key).cloned();
+        let mut result = match hit {
+            Some(result) => result,
+            None => {
+                // the memory tier stays unlocked while reading the file
+                let result = self.disk.as_ref()?.get(key)?;
+                self.memory.lock().unwrap().insert(key, result.clone(), 1);
+                result
+            }
+        };
+        result.cached = true;
+        Some(result)
+    }
+
+    pub fn insert(&self, key: &str, result: &CaptionResult) {
+        self.memory.lock().unwrap().insert(key, result.clone(), 1);
+        if let Some(disk) = &self.disk {
+            if let Err(e) = disk.insert(key, result) {
+                println!("could not write caption {key} to the disk cache: {e}");
+            }
+        }
+    }
+
+    /// Returns the cached caption for the image with `image_id` and `options`, or runs `caption`
+    /// and caches what it produces.
+    pub fn get_or_caption(
+        &self,
+        image_id: &str,
+        options: &CaptionOptions,
+        caption: impl FnOnce() -> anyhow::Result<CaptionResult>,
+    ) -> anyhow::Result<CaptionResult> {
+        let model = models::registry().resolve_config(options.model.as_deref(), options.quantized)?;
+        let key = cache_key(image_id, model, options);
+        if let Some(result) = self.get(&key) {
+            println!("caption cache hit for {key}");
+            return Ok(result);
+        }
+        let result = caption()?;
+        self.insert(&key, &result);
+        Ok(result)
+    }
+
+    /// Drops every cached caption from both tiers.
+    pub fn clear(&self) -> anyhow::Result<()> {
+        self.memory.lock().unwrap().clear();
+        if let Some(disk) = &self.disk {
+            disk.clear()?;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let memory = self.memory.lock().unwrap();
+        let disk = self.disk.as_ref().map(DiskTier::usage);
+        CacheStats {
+            memory_entries: memory.len(),
+            memory_capacity: memory.budget(),
+            disk_entries: disk.map(|(entries, _)| entries),
+            disk_bytes: disk.map(|(_, bytes)| bytes),
+            disk_max_bytes: self.disk.as_ref().map(|disk| disk.max_bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_image::{ImageInfo, Orientation};
+    use crate::models::{RegistryConfig, WeightDtype};
+
+    fn result(caption: &str) -> CaptionResult {
+        CaptionResult {
+            caption: caption.to_string(),
+            image_id: "abc".to_string(),
+            cached: false,
+            image: ImageInfo {
+                width: 4,
+                height: 4,
+                orientation: Orientation::Normal,
+                resize: Default::default(),
+                filter: Default::default(),
+                pad_color: None,
+                background: None,
+                transparency: None,
+            },
+            regions: Vec::new(),
+            tiles: Vec::new(),
+            frames: Vec::new(),
+            distinct_captions: Vec::new(),
+            candidates: Vec::new(),
+        }
+    }
+
+    fn model() -> ModelConfig {
+        RegistryConfig::builtin().models.remove(0)
+    }
+
+    #[test]
+    fn key_ignores_where_the_caption_goes() {
+        let options = CaptionOptions::default();
+        let routed = CaptionOptions {
+            channel: Some("news".to_string()),
+            image_id: Some("abc".to_string()),
+            index: true,
+            model: Some(model().name),
+            ..CaptionOptions::default()
+        };
+        assert_eq!(cache_key("abc", &model(), &options), cache_key("abc", &model(), &routed));
+    }
+
+    #[test]
+    fn key_covers_the_image_and_the_generation_options() {
+        let options = CaptionOptions::default();
+        let sampled = CaptionOptions { temperature: Some(0.7), ..CaptionOptions::default() };
+        assert_ne!(cache_key("abc", &model(), &options), cache_key("abd", &model(), &options));
+        assert_ne!(cache_key("abc", &model(), &options), cache_key("abc", &model(), &sampled));
+    }
+
+    #[test]
+    fn key_follows_the_checkpoint_behind_a_name() {
+        let options = CaptionOptions::default();
+        let moved = ModelConfig { weights: Some("other.safetensors".to_string()), ..model() };
+        let half = ModelConfig { dtype: WeightDtype::F16, ..model() };
+        assert_ne!(cache_key("abc", &model(), &options), cache_key("abc", &moved, &options));
+        assert_ne!(cache_key("abc", &model(), &options), cache_key("abc", &half, &options));
+    }
+
+    #[test]
+    fn default_and_named_model_share_entries() {
+        let cache = CaptionCache::new(4, None, 0).unwrap();
+        let named = CaptionOptions { model: Some(models::registry().resolve(None, false).unwrap().to_string()), ..CaptionOptions::default() };
+        cache.get_or_caption("abc", &CaptionOptions::default(), || Ok(result("a cat"))).unwrap();
+        assert!(cache.get_or_caption("abc", &named, || anyhow::bail!("should be cached")).unwrap().cached);
+    }
+
+    #[test]
+    fn disk_hits_survive_a_new_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        CaptionCache::new(4, Some(dir.path()), 1 << 20).unwrap().insert("k", &result("a cat"));
+        let cache = CaptionCache::new(4, Some(dir.path()), 1 << 20).unwrap();
+        let hit = cache.get("k").unwrap();
+        assert_eq!(hit.caption, "a cat");
+        assert!(hit.cached);
+        assert_eq!(cache.stats().memory_entries, 1);
+    }
+
+    #[test]
+    fn disk_tier_is_trimmed_to_its_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CaptionCache::new(0, Some(dir.path()), 1).unwrap();
+        cache.insert("k", &result("a cat"));
+        assert!(cache.get("k").is_none());
+        assert_eq!(cache.stats().disk_entries, Some(0));
+    }
+
+    #[test]
+    fn disk_tier_evicts_least_recently_used_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let size = serde_json::to_vec(&result("a cat")).unwrap().len() as u64;
+        let cache = CaptionCache::new(0, Some(dir.path()), 2 * size).unwrap();
+        cache.insert("a", &result("a cat"));
+        cache.insert("b", &result("a dog"));
+        assert!(cache.get("a").is_some());
+        cache.insert("c", &result("a cow"));
+        assert!(cache.get("b").is_none());
+        assert!(!dir.path().join("b.json").exists());
+        assert_eq!(cache.stats().disk_entries, Some(2));
+        assert_eq!(cache.stats().disk_bytes, Some(2 * size));
+    }
+
+    #[test]
+    fn reopening_trims_to_a_smaller_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CaptionCache::new(0, Some(dir.path()), 1 << 20).unwrap();
+        for key in ["a", "b", "c"] {
+            cache.insert(key, &result("a cat"));
+        }
+        let size = serde_json::to_vec(&result("a cat")).unwrap().len() as u64;
+        let cache = CaptionCache::new(0, Some(dir.path()), size).unwrap();
+        assert_eq!(cache.stats().disk_entries, Some(1));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn get_or_caption_runs_once() {
+        let cache = CaptionCache::new(4, None, 0).unwrap();
+        let options = CaptionOptions::default();
+        let first = cache.get_or_caption("abc", &options, || Ok(result("a cat"))).unwrap();
+        assert!(!first.cached);
+        let second = cache.get_or_caption("abc", &options, || anyhow::bail!("should be cached")).unwrap();
+        assert_eq!(second.caption, "a cat");
+        assert!(second.cached);
+    }
+}
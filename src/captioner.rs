@@ -0,0 +1,113 @@
+use candle_core::Tensor;
+use crate::backend::CaptionBackend;
+use crate::load_image::{ImageSpec, PreprocessError};
+use crate::models;
+use crate::options::CaptionOptions;
+use crate::rerank;
+use crate::token_output_stream::TokenOutputStream;
+use anyhow::Error as E;
+
+/// How many images go through the vision encoder in one forward pass.
+pub const MAX_ENCODE_BATCH: usize = 8;
+
+/// A registered model ready to caption many images. The weights are shared with every other
+/// captioner of the same model, the decoding state is its own.
+pub struct Captioner {
+    model: String,
+    backend: Box<dyn CaptionBackend>,
+    tokenizer: TokenOutputStream,
+}
+
+impl Captioner {
+    /// Takes the model a request selected with its `model` and `quantized` options from the
+    /// registry, loading it when it is the first request for it.
+    pub fn load(model: Option<&str>, quantized: bool) -> anyhow::Result<Self> {
+        let registry = models::registry();
+        let model = registry.resolve(model, quantized)?;
+        let backend = registry.load(model)?.backend.boxed_clone();
+        let tokenizer = TokenOutputStream::new(backend.tokenizer().clone());
+        Ok(Self { model: model.to_string(), backend, tokenizer })
+    }
+
+    /// Registry name of the model.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// How images must be preprocessed for [`Self::encode`].
+    pub fn image_spec(&self) -> &ImageSpec {
+        self.backend.image_spec()
+    }
+
+    /// Runs the vision encoder over a preprocessed (3, size, size) image and returns its
+    /// embeddings with a leading batch dimension of one.
+    pub fn encode(&self, image: &Tensor) -> anyhow::Result<Tensor> {
+        self.encode_batch(std::slice::from_ref(image))
+    }
+
+    /// Encodes several preprocessed images, batching the vision encoder passes. Row `i` of the
+    /// result holds the embeddings of `images[i]`.
+    pub fn encode_batch(&self, images: &[Tensor]) -> anyhow::Result<Tensor> {
+        let mut embeds = Vec::with_capacity(images.len().div_ceil(MAX_ENCODE_BATCH));
+        for chunk in images.chunks(MAX_ENCODE_BATCH) {
+            let batch = Tensor::stack(chunk, 0)?.to_device(self.backend.device())?;
+            embeds.push(self.backend.encode(&batch)?);
+        }
+        Ok(Tensor::cat(&embeds, 0)?)
+    }
+
+    /// Rejects options that are out of range or that the model cannot honour, before any image is
+    /// encoded for them.
+    pub fn check_options(&self, options: &CaptionOptions) -> Result<(), PreprocessError> {
+        if let Some(n) = options.best_of {
+            rerank::check_best_of(n)?;
+        }
+        let limit = self.backend.max_tokens();
+        match options.max_tokens {
+            Some(max_tokens) if max_tokens > limit => Err(PreprocessError::InvalidRequest(format!(
+                "max_tokens must be at most {limit} for model {}, got {max_tokens}",
+                self.model
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decodes a caption for `image_embeds`, handing every streamed piece of text to `on_token`
+    /// as soon as the tokenizer can produce it.
+    pub fn generate(
+        &mut self,
+        image_embeds: &Tensor,
+        options: &CaptionOptions,
+        mut on_token: impl FnMut(String) -> anyhow::Result<()>,
+    ) -> anyhow::Result<String> {
+        self.backend.reset();
+        self.tokenizer.clear();
+        let mut logits_processor =
+            candle_transformers::generation::LogitsProcessor::new(options.seed, options.temperature, options.top_p);
+
+        let special = self.backend.special_tokens();
+        let mut token_ids = vec![special.bos];
+        let mut result = String::from("");
+        let max_tokens = options.max_tokens.unwrap_or(usize::MAX).min(self.backend.max_tokens());
+        for index in 0..max_tokens {
+            let context_size = if index > 0 { 1 } else { token_ids.len() };
+            let start_pos = token_ids.len().saturating_sub(context_size);
+            let input_ids = Tensor::new(&token_ids[start_pos..], self.backend.device())?.unsqueeze(0)?;
+            let logits = self.backend.decode_step(&input_ids, image_embeds)?;
+            let token = logits_processor.sample(&logits)?;
+            if token == special.eos {
+                break;
+            }
+            token_ids.push(token);
+            if let Some(t) = self.tokenizer.next_token(token)? {
+                result += &*t;
+                on_token(t)?;
+            }
+        }
+        if let Some(rest) = self.tokenizer.decode_rest().map_err(E::msg)? {
+            result += &*rest;
+            on_token(rest)?;
+        }
+        Ok(result)
+    }
+}
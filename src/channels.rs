@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use axum::extract::ws::Message;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use crate::ws_protocol::ServerEvent;
+
+/// Every caption is published here, whatever channel the request named. Only connections that
+/// present the admin token may subscribe to it.
+pub const FIREHOSE: &str = "all";
+
+/// How many channels one WebSocket connection may subscribe to.
+const MAX_SUBSCRIPTIONS: usize = 16;
+
+/// How many channels with subscribers the hub keeps at once.
+const MAX_CHANNELS: usize = 1024;
+
+const MAX_CHANNEL_NAME: usize = 64;
+
+/// A caption event as seen by channel subscribers: where it came from and what happened.
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub channel: String,
+    pub source: &'static str,
+    pub event: ServerEvent,
+}
+
+/// Named broadcast channels that observers can subscribe to over the WebSocket. Channels are
+/// created by their first subscriber and forgotten once nobody listens anymore, so publishing to
+/// a channel nobody watches costs nothing.
+pub struct ChannelHub {
+    capacity: usize,
+    channels: Mutex<HashMap<String, broadcast::Sender<Published>>>,
+}
+
+impl ChannelHub {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Subscribes a connection that already holds `held` subscriptions to `channel`. Only `admin`
+    /// connections may watch the firehose.
+    pub fn subscribe(&self, channel: &str, admin: bool, held: usize) -> anyhow::Result<broadcast::Receiver<Published>> {
+        if channel.is_empty() || channel.len() > MAX_CHANNEL_NAME {
+            anyhow::bail!("channel names must be 1 to {MAX_CHANNEL_NAME} bytes long");
+        }
+        if channel == FIREHOSE && !admin {
+            anyhow::bail!("the {FIREHOSE:?} channel needs the admin token");
+        }
+        if held >= MAX_SUBSCRIPTIONS {
+            anyhow::bail!("at most {MAX_SUBSCRIPTIONS} subscriptions per connection");
+        }
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(channel) {
+            // subscriptions that ended leave their channels behind until something notices
+            channels.retain(|_, sender| sender.receiver_count() > 0);
+            if channels.len() >= MAX_CHANNELS {
+                anyhow::bail!("too many channels are in use, try again later");
+            }
+        }
+        let sender = channels
+            .entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(self.capacity).0);
+        Ok(sender.subscribe())
+    }
+
+    fn publish_to(&self, channel: &str, source: &'static str, event: &ServerEvent) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(channel) {
+            let published = Published {
+                channel: channel.to_string(),
+                source,
+                event: event.clone(),
+            };
+            if sender.send(published).is_err() {
+                // the last subscriber is gone
+                channels.remove(channel);
+            }
+        }
+    }
+
+    /// Returns a handle that publishes events from one request to the firehose and, when the
+    /// request named one, to its own channel.
+    pub fn publisher(&self, source: &'static str, channel: Option<&str>) -> Publisher<'_> {
+        Publisher {
+            hub: self,
+            source,
+            channel: channel.filter(|c| *c != FIREHOSE).map(String::from),
+        }
+    }
+}
+
+pub struct Publisher<'a> {
+    hub: &'a ChannelHub,
+    source: &'static str,
+    channel: Option<String>,
+}
+
+impl Publisher<'_> {
+    pub fn publish(&self, event: &ServerEvent) {
+        self.hub.publish_to(FIREHOSE, self.source, event);
+        if let Some(channel) = &self.channel {
+            self.hub.publish_to(channel, self.source, event);
+        }
+    }
+}
+
+/// Forwards one channel to a WebSocket connection until dropped. A subscriber that falls behind
+/// is told how many events it missed instead of slowing down everybody else.
+pub struct Subscription(JoinHandle<()>);
+
+impl Subscription {
+    pub fn start(channel: String, mut receiver: broadcast::Receiver<Published>, outbox: mpsc::Sender<Message>) -> Self {
+        Self(tokio::spawn(async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(published) => ServerEvent::Channel {
+                        channel: published.channel,
+                        source: published.source.to_string(),
+                        event: Box::new(published.event),
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => ServerEvent::Lagged {
+                        channel: channel.clone(),
+                        skipped,
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if outbox.send(event.to_message()).await.is_err() {
+                    break;
+                }
+            }
+        }))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_firehose_needs_the_admin_token() {
+        let hub = ChannelHub::new(4);
+        let error = hub.subscribe(FIREHOSE, false, 0).unwrap_err();
+        assert_eq!(error.to_string(), "the \"all\" channel needs the admin token");
+        assert!(hub.subscribe(FIREHOSE, true, 0).is_ok());
+        assert!(hub.subscribe("kitchen", false, 0).is_ok());
+    }
+
+    #[test]
+    fn subscriptions_per_connection_are_capped() {
+        let hub = ChannelHub::new(4);
+        assert!(hub.subscribe("kitchen", false, MAX_SUBSCRIPTIONS - 1).is_ok());
+        let error = hub.subscribe("kitchen", false, MAX_SUBSCRIPTIONS).unwrap_err();
+        assert_eq!(error.to_string(), format!("at most {MAX_SUBSCRIPTIONS} subscriptions per connection"));
+        assert!(hub.subscribe(FIREHOSE, true, MAX_SUBSCRIPTIONS).is_err());
+    }
+
+    #[test]
+    fn channel_names_are_checked() {
+        let hub = ChannelHub::new(4);
+        assert!(hub.subscribe("", false, 0).is_err());
+        assert!(hub.subscribe(&"x".repeat(MAX_CHANNEL_NAME + 1), false, 0).is_err());
+        assert!(hub.subscribe(&"x".repeat(MAX_CHANNEL_NAME), false, 0).is_ok());
+    }
+
+    fn parse_event(message: Message) -> serde_json::Value {
+        let Message::Text(text) = message else { panic!("expected a text frame") };
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_are_told_what_they_missed() {
+        let hub = ChannelHub::new(2);
+        let receiver = hub.subscribe("kitchen", false, 0).unwrap();
+        let (outbox, mut inbox) = mpsc::channel(16);
+        let _subscription = Subscription::start("kitchen".to_string(), receiver, outbox);
+
+        // the subscription task cannot run until this test yields, so it falls behind
+        let publisher = hub.publisher("test", Some("kitchen"));
+        for n in 0..5 {
+            publisher.publish(&ServerEvent::error(None, n));
+        }
+
+        let lagged = parse_event(inbox.recv().await.unwrap());
+        assert_eq!(lagged["type"], "lagged");
+        assert_eq!(lagged["channel"], "kitchen");
+        assert_eq!(lagged["skipped"], 3);
+        for n in 3..5 {
+            let event = parse_event(inbox.recv().await.unwrap());
+            assert_eq!(event["type"], "channel");
+            assert_eq!(event["event"]["message"], n.to_string());
+        }
+    }
+}
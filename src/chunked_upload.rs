@@ -0,0 +1,140 @@
+use sha2::{Digest, Sha256};
+use crate::options::CaptionOptions;
+
+/// Most memory reserved for an upload before its chunks arrive. The announced size is only a
+/// claim, so the buffer grows with the data actually received beyond this.
+const INITIAL_RESERVE: usize = 64 * 1024;
+
+/// An image arriving in pieces over the WebSocket, for proxies that cap the frame size.
+///
+/// The client sends `upload_begin` with the total size and content type, then binary frames that
+/// each start with a 4 byte big-endian chunk number followed by the payload, then `upload_end`
+/// with the SHA-256 of the whole image. Sizes are checked before anything is buffered.
+pub struct ChunkedUpload {
+    pub id: String,
+    options: CaptionOptions,
+    size: usize,
+    next_seq: u32,
+    data: Vec<u8>,
+}
+
+impl ChunkedUpload {
+    pub fn begin(id: String, size: usize, content_type: &str, options: CaptionOptions, max_bytes: usize) -> anyhow::Result<Self> {
+        if !content_type.starts_with("image/") {
+            anyhow::bail!("unsupported content type `{content_type}`, expected an image");
+        }
+        if size == 0 {
+            anyhow::bail!("upload is empty");
+        }
+        if size > max_bytes {
+            anyhow::bail!("upload of {size} bytes exceeds the limit of {max_bytes} bytes");
+        }
+        Ok(Self {
+            id,
+            options,
+            size,
+            next_seq: 0,
+            data: Vec::with_capacity(size.min(INITIAL_RESERVE)),
+        })
+    }
+
+    /// Appends one binary chunk frame. Chunks must arrive in order and may not grow the upload
+    /// past the size announced in `upload_begin`.
+    pub fn push_chunk(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        let Some((seq, payload)) = frame.split_first_chunk::<4>() else {
+            anyhow::bail!("chunk frame is missing its 4 byte sequence number");
+        };
+        let seq = u32::from_be_bytes(*seq);
+        if seq != self.next_seq {
+            anyhow::bail!("expected chunk {}, got chunk {seq}", self.next_seq);
+        }
+        if self.data.len() + payload.len() > self.size {
+            anyhow::bail!("chunk {seq} overflows the announced size of {} bytes", self.size);
+        }
+        self.data.extend_from_slice(payload);
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// Checks that the image is complete and matches `sha256` (hex), returning it with the
+    /// options it should be captioned with.
+    pub fn finish(self, sha256: &str) -> anyhow::Result<(Vec<u8>, CaptionOptions)> {
+        if self.data.len() != self.size {
+            anyhow::bail!("upload incomplete: received {} of {} bytes", self.data.len(), self.size);
+        }
+        let digest = format!("{:x}", Sha256::digest(&self.data));
+        if !digest.eq_ignore_ascii_case(sha256) {
+            anyhow::bail!("checksum mismatch: expected {sha256}, got {digest}");
+        }
+        Ok((self.data, self.options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = seq.to_be_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn upload(size: usize) -> ChunkedUpload {
+        ChunkedUpload::begin("a".to_string(), size, "image/png", CaptionOptions::default(), 16).unwrap()
+    }
+
+    #[test]
+    fn begin_checks_type_and_size() {
+        let begin = |size, content_type| ChunkedUpload::begin("a".to_string(), size, content_type, CaptionOptions::default(), 16);
+        assert!(begin(8, "text/plain").is_err());
+        assert!(begin(0, "image/png").is_err());
+        assert!(begin(17, "image/png").is_err());
+        assert!(begin(16, "image/png").is_ok());
+    }
+
+    #[test]
+    fn reassembles_chunks_in_order() {
+        let mut upload = upload(6);
+        upload.push_chunk(&chunk(0, b"abc")).unwrap();
+        upload.push_chunk(&chunk(1, b"def")).unwrap();
+        let digest = format!("{:x}", Sha256::digest(b"abcdef"));
+        let (data, _) = upload.finish(&digest.to_uppercase()).unwrap();
+        assert_eq!(data, b"abcdef");
+    }
+
+    #[test]
+    fn rejects_out_of_order_and_short_chunks() {
+        let mut upload = upload(6);
+        assert!(upload.push_chunk(&[0, 0, 0]).is_err());
+        assert!(upload.push_chunk(&chunk(1, b"abc")).is_err());
+        upload.push_chunk(&chunk(0, b"abc")).unwrap();
+        assert!(upload.push_chunk(&chunk(0, b"abc")).is_err());
+    }
+
+    #[test]
+    fn rejects_chunks_past_the_announced_size() {
+        let mut upload = upload(4);
+        upload.push_chunk(&chunk(0, b"abc")).unwrap();
+        assert!(upload.push_chunk(&chunk(1, b"de")).is_err());
+    }
+
+    #[test]
+    fn finish_checks_length_and_checksum() {
+        let mut incomplete = upload(6);
+        incomplete.push_chunk(&chunk(0, b"abc")).unwrap();
+        let digest = format!("{:x}", Sha256::digest(b"abc"));
+        assert!(incomplete.finish(&digest).is_err());
+
+        let mut corrupt = upload(3);
+        corrupt.push_chunk(&chunk(0, b"abc")).unwrap();
+        assert!(corrupt.finish(&format!("{:x}", Sha256::digest(b"abd"))).is_err());
+    }
+
+    #[test]
+    fn announced_size_is_not_reserved_up_front() {
+        let size = 1 << 30;
+        let upload = ChunkedUpload::begin("a".to_string(), size, "image/png", CaptionOptions::default(), size).unwrap();
+        assert!(upload.data.capacity() <= INITIAL_RESERVE);
+    }
+}
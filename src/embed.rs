@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use candle_core::Tensor;
+use serde::{Deserialize, Serialize};
+use crate::caption_cache::image_hash;
+use crate::captioner::Captioner;
+use crate::embedding_cache::{embedding_key, CachedEmbedding, EmbeddingCache};
+use crate::models;
+use crate::load_image::{decode_image, image_to_tensor, ImageLimits, PreprocessOptions};
+
+/// How the per-patch output of the vision encoder is reduced to a single vector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    /// The class token, which BLIP's own image-text heads use as the image summary.
+    #[default]
+    Cls,
+    /// The mean of the patch tokens, leaving out the class token.
+    Mean,
+}
+
+impl Pooling {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Cls => "cls",
+            Self::Mean => "mean",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingFormat {
+    /// A JSON object with the vector as a float array.
+    #[default]
+    Json,
+    /// A safetensors file holding one `embedding` tensor.
+    Safetensors,
+    /// The bare vector as little-endian f32.
+    Raw,
+}
+
+impl EmbeddingFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Safetensors | Self::Raw => "application/octet-stream",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EmbedOptions {
+    /// Registered model whose vision encoder produces the embedding, the default one when unset.
+    pub model: Option<String>,
+    pub pooling: Pooling,
+    /// Scale the vector to unit L2 norm, so dot products are cosine similarities.
+    pub normalize: bool,
+    pub format: EmbeddingFormat,
+    /// Without a `model`, use the default quantized model. A `model` named alongside it must be
+    /// quantized.
+    pub quantized: bool,
+    #[serde(flatten)]
+    pub preprocess: PreprocessOptions,
+}
+
+/// A pooled image embedding from a backend's vision encoder.
+#[derive(Debug, Clone, Serialize)]
+pub struct Embedding {
+    pub image_id: String,
+    pub model: String,
+    pub pooling: Pooling,
+    pub normalized: bool,
+    pub dim: usize,
+    pub embedding: Vec<f32>,
+}
+
+impl Embedding {
+    /// Writes the embedding in `format`. Only JSON carries the metadata fields inline, safetensors
+    /// keeps them in its header and raw output is just the floats.
+    pub fn encode(&self, format: EmbeddingFormat) -> anyhow::Result<Vec<u8>> {
+        Ok(match format {
+            EmbeddingFormat::Json => serde_json::to_vec(self)?,
+            EmbeddingFormat::Safetensors => {
+                let tensor = Tensor::new(self.embedding.as_slice(), &candle_core::Device::Cpu)?;
+                let metadata = HashMap::from([
+                    ("image_id".to_string(), self.image_id.clone()),
+                    ("model".to_string(), self.model.clone()),
+                    ("pooling".to_string(), self.pooling.as_str().to_string()),
+                    ("normalized".to_string(), self.normalized.to_string()),
+                ]);
+                safetensors::serialize([("embedding", &tensor)], &Some(metadata))?
+            }
+            EmbeddingFormat::Raw => self.embedding.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        })
+    }
+}
+
+/// Reduces vision encoder output of shape (1, patches, hidden) to a (hidden,) vector.
+pub fn pool(embeds: &Tensor, pooling: Pooling, normalize: bool) -> candle_core::Result<Tensor> {
+    let embeds = embeds.squeeze(0)?;
+    let pooled = match pooling {
+        Pooling::Cls => embeds.get(0)?,
+        Pooling::Mean => embeds.narrow(0, 1, embeds.dim(0)? - 1)?.mean(0)?,
+    };
+    if normalize {
+        let norm = pooled.sqr()?.sum_all()?.sqrt()?;
+        pooled.broadcast_div(&norm)
+    } else {
+        Ok(pooled)
+    }
+}
+
+/// Runs `image` through the vision encoder, or takes its embeddings from `embeddings` when an
+/// earlier request already did, and pools them as requested.
+pub fn embed_image(image: &[u8], options: &EmbedOptions, limits: &ImageLimits, embeddings: &EmbeddingCache) -> anyhow::Result<Embedding> {
+    let image_id = image_hash(image);
+    let model = models::registry().resolve(options.model.as_deref(), options.quantized)?;
+    let key = embedding_key(&image_id, model, &options.preprocess);
+    let embeds = match embeddings.get(&key) {
+        Some(cached) => cached.embeds,
+        None => {
+            // decoding first turns oversized or corrupt uploads away before a model is loaded
+            let (img, info) = decode_image(image, &options.preprocess, limits)?;
+            let captioner = Captioner::load(Some(model), false)?;
+            let embeds = captioner.encode(&image_to_tensor(&img, &options.preprocess, captioner.image_spec())?)?;
+            embeddings.insert(&key, CachedEmbedding { embeds: embeds.clone(), info });
+            embeds
+        }
+    };
+    let embedding = pool(&embeds, options.pooling, options.normalize)?.to_vec1::<f32>()?;
+    Ok(Embedding {
+        image_id,
+        model: model.to_string(),
+        pooling: options.pooling,
+        normalized: options.normalize,
+        dim: embedding.len(),
+        embedding,
+    })
+}
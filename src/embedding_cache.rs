@@ -0,0 +1,110 @@
+use std::sync::Mutex;
+use candle_core::Tensor;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::load_image::{ImageInfo, PreprocessOptions};
+use crate::lru::Lru;
+
+/// Vision encoder output for one whole image, plus what preprocessing reported about it.
+#[derive(Debug, Clone)]
+pub struct CachedEmbedding {
+    /// Shape (1, patches, hidden), ready for `Captioner::generate`.
+    pub embeds: Tensor,
+    pub info: ImageInfo,
+}
+
+/// Key of an embedding: the image id plus what changes the encoder output, i.e. the registered
+/// model and the preprocessing. Sampling settings are left out, that is the point of the cache.
+pub fn embedding_key(image_id: &str, model: &str, preprocess: &PreprocessOptions) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(image_id.as_bytes());
+    // length-prefixed so the model name cannot run into the bytes after it
+    hasher.update((model.len() as u64).to_le_bytes());
+    hasher.update(model.as_bytes());
+    hasher.update(serde_json::to_vec(preprocess).expect("options always serialize"));
+    hasher.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub budget_bytes: usize,
+}
+
+/// Keeps the `image_embeds` of recent uploads within a memory budget, so re-captioning an image
+/// with other sampling settings skips the vision encoder, and follow-up requests can reference the
+/// image by its `image_id` instead of uploading it again.
+pub struct EmbeddingCache {
+    lru: Mutex<Lru<CachedEmbedding>>,
+}
+
+impl EmbeddingCache {
+    /// A cache holding at most `budget_bytes` of embeddings, 0 disables it.
+    pub fn new(budget_bytes: usize) -> Self {
+        Self { lru: Mutex::new(Lru::new(budget_bytes)) }
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedEmbedding> {
+        self.lru.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, embedding: CachedEmbedding) {
+        let bytes = embedding.embeds.elem_count() * embedding.embeds.dtype().size_in_bytes();
+        self.lru.lock().unwrap().insert(key, embedding, bytes);
+    }
+
+    pub fn clear(&self) {
+        self.lru.lock().unwrap().clear();
+    }
+
+    pub fn stats(&self) -> EmbeddingStats {
+        let lru = self.lru.lock().unwrap();
+        EmbeddingStats { entries: lru.len(), bytes: lru.used(), budget_bytes: lru.budget() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+    use crate::load_image::{Orientation, ResizeMode};
+
+    fn embedding(patches: usize) -> CachedEmbedding {
+        let info = ImageInfo {
+            width: 4,
+            height: 4,
+            orientation: Orientation::Normal,
+            resize: Default::default(),
+            filter: Default::default(),
+            pad_color: None,
+            background: None,
+            transparency: None,
+        };
+        CachedEmbedding { embeds: Tensor::zeros((1, patches, 4), DType::F32, &Device::Cpu).unwrap(), info }
+    }
+
+    #[test]
+    fn key_covers_model_and_preprocessing() {
+        let preprocess = PreprocessOptions::default();
+        let letterboxed = PreprocessOptions { resize: ResizeMode::Letterbox, ..PreprocessOptions::default() };
+        let key = embedding_key("abc", "blip-large", &preprocess);
+        assert_eq!(key, embedding_key("abc", "blip-large", &preprocess));
+        assert_ne!(key, embedding_key("abd", "blip-large", &preprocess));
+        assert_ne!(key, embedding_key("abc", "blip-large-q4k", &preprocess));
+        assert_ne!(key, embedding_key("abc", "blip-large", &letterboxed));
+    }
+
+    #[test]
+    fn budget_is_in_bytes() {
+        // each embedding is 2 x 4 f32 values, 32 bytes
+        let cache = EmbeddingCache::new(64);
+        cache.insert("a", embedding(2));
+        cache.insert("b", embedding(2));
+        cache.insert("c", embedding(2));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (2, 64));
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("c").is_some());
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Tracks how long a WebSocket connection has gone without a request from its client.
+pub struct IdleTimer {
+    timeout: Option<Duration>,
+    last_activity: Instant,
+}
+
+impl IdleTimer {
+    /// A timer that expires after `timeout` without activity, or never when there is none.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self { timeout, last_activity: Instant::now() }
+    }
+
+    /// Records client activity, restarting the timeout.
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Resolves once the connection has been idle for the timeout, or never when there is none.
+    pub async fn expired(&self) {
+        match self.timeout {
+            Some(timeout) => tokio::time::sleep_until(self.last_activity + timeout).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Decides what to do once [`Self::expired`] resolves. A connection that still has work in
+    /// flight or events coming is not idle, so the timer restarts instead of closing it.
+    pub fn should_close(&mut self, busy: bool) -> bool {
+        if busy {
+            self.touch();
+        }
+        !busy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(20);
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn idle_connections_are_closed() {
+        let mut timer = IdleTimer::new(Some(TIMEOUT));
+        tokio::time::timeout(WAIT, timer.expired()).await.expect("the timer should expire");
+        assert!(timer.should_close(false));
+    }
+
+    #[tokio::test]
+    async fn busy_connections_restart_the_timer() {
+        let mut timer = IdleTimer::new(Some(TIMEOUT));
+        tokio::time::timeout(WAIT, timer.expired()).await.unwrap();
+        assert!(!timer.should_close(true));
+        assert!(tokio::time::timeout(TIMEOUT / 4, timer.expired()).await.is_err());
+        tokio::time::timeout(WAIT, timer.expired()).await.unwrap();
+        assert!(timer.should_close(false));
+    }
+
+    #[tokio::test]
+    async fn activity_pushes_the_deadline_back() {
+        let mut timer = IdleTimer::new(Some(TIMEOUT));
+        tokio::time::sleep(TIMEOUT / 2).await;
+        timer.touch();
+        let started = Instant::now();
+        tokio::time::timeout(WAIT, timer.expired()).await.unwrap();
+        assert!(started.elapsed() >= TIMEOUT / 2);
+    }
+
+    #[tokio::test]
+    async fn without_a_timeout_connections_stay_open() {
+        let timer = IdleTimer::new(None);
+        assert!(tokio::time::timeout(TIMEOUT * 2, timer.expired()).await.is_err());
+    }
+}
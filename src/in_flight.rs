@@ -0,0 +1,87 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The requests a WebSocket connection has running. Ids must be unique among them and at most
+/// `limit` run at once; anything else is turned away rather than queued.
+pub struct InFlight {
+    ids: Arc<Mutex<HashSet<String>>>,
+    permits: Arc<Semaphore>,
+    limit: usize,
+}
+
+/// Holds a request's id and its slot until the request finishes and this is dropped.
+pub struct Admission {
+    id: String,
+    ids: Arc<Mutex<HashSet<String>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl InFlight {
+    pub fn new(limit: usize) -> Self {
+        Self { ids: Arc::new(Mutex::new(HashSet::new())), permits: Arc::new(Semaphore::new(limit)), limit }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.lock().unwrap().is_empty()
+    }
+
+    /// Claims `id` and one of the slots, or says why the request is rejected.
+    pub fn admit(&self, id: &str) -> Result<Admission, String> {
+        if !self.ids.lock().unwrap().insert(id.to_string()) {
+            return Err("a request with this id is already in flight".to_string());
+        }
+        match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Admission { id: id.to_string(), ids: self.ids.clone(), _permit: permit }),
+            Err(_) => {
+                self.ids.lock().unwrap().remove(id);
+                Err(format!("too many requests in flight (limit {})", self.limit))
+            }
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.ids.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_ids_are_rejected_until_the_first_finishes() {
+        let in_flight = InFlight::new(4);
+        let first = in_flight.admit("a").unwrap();
+        let error = in_flight.admit("a").err().unwrap();
+        assert_eq!(error, "a request with this id is already in flight");
+        assert!(in_flight.admit("b").is_ok());
+
+        drop(first);
+        assert!(in_flight.admit("a").is_ok());
+    }
+
+    #[test]
+    fn at_most_limit_requests_run_at_once() {
+        let in_flight = InFlight::new(2);
+        let a = in_flight.admit("a").unwrap();
+        let _b = in_flight.admit("b").unwrap();
+        let error = in_flight.admit("c").err().unwrap();
+        assert_eq!(error, "too many requests in flight (limit 2)");
+        // the rejected id is not left behind
+        drop(a);
+        let _c = in_flight.admit("c").unwrap();
+        assert!(!in_flight.is_empty());
+    }
+
+    #[test]
+    fn finished_requests_free_their_slot() {
+        let in_flight = InFlight::new(1);
+        assert!(in_flight.is_empty());
+        drop(in_flight.admit("a").unwrap());
+        assert!(in_flight.is_empty());
+        assert!(in_flight.admit("b").is_ok());
+    }
+}
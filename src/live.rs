@@ -0,0 +1,294 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use axum::extract::ws::Message;
+use candle_core::IndexOp;
+use serde::Deserialize;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::captioner::Captioner;
+use crate::channels::ChannelHub;
+use crate::options::CaptionOptions;
+use crate::load_image::{load_image, ImageLimits};
+use crate::ws_protocol::{ServerEvent, Stage};
+
+/// Upper bound on the caption rate a client may ask for.
+const MAX_FPS: f64 = 30.0;
+
+/// How a live session samples and filters the frames it receives.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LiveSettings {
+    /// How many frames per second to caption at most. Frames arriving faster than this, or while
+    /// a caption is still running, are dropped in favour of the newest one.
+    pub fps: f64,
+    /// Frames whose vision embedding is at least this similar (cosine) to the last captioned frame
+    /// are treated as the same scene and not captioned again.
+    pub similarity_threshold: f32,
+    pub options: CaptionOptions,
+}
+
+impl Default for LiveSettings {
+    fn default() -> Self {
+        Self {
+            fps: 2.0,
+            similarity_threshold: 0.95,
+            options: CaptionOptions::default(),
+        }
+    }
+}
+
+struct Frame {
+    index: u64,
+    data: Vec<u8>,
+}
+
+/// The newest frame waiting for the worker, shared between the session and its worker.
+#[derive(Default)]
+struct FrameSlot {
+    frame: Mutex<Option<Frame>>,
+    notify: Notify,
+    /// Frames replaced before the worker got to them since the last `live_caption` event.
+    dropped: AtomicU64,
+}
+
+/// A rolling caption over a stream of frames. Only the newest frame is kept; the worker captions
+/// it whenever it is free and the frame rate allows, and emits `live_caption` events only when the
+/// scene actually changed.
+pub struct LiveSession {
+    pub id: String,
+    slot: Arc<FrameSlot>,
+    next_index: u64,
+    task: JoinHandle<()>,
+}
+
+impl LiveSession {
+    /// Starts a session in `live` unless one is already running there. The settings are checked
+    /// and the model loaded before anything is spawned, so `live` is only filled once the session
+    /// is running and a failed start can simply be retried.
+    pub async fn start(
+        live: &mut Option<LiveSession>,
+        id: String,
+        settings: LiveSettings,
+        outbox: mpsc::Sender<Message>,
+        hub: Arc<ChannelHub>,
+        limits: ImageLimits,
+    ) -> anyhow::Result<()> {
+        if let Some(running) = live {
+            anyhow::bail!("live session {} is already running", running.id);
+        }
+        if !(settings.fps > 0.0 && settings.fps <= MAX_FPS) {
+            anyhow::bail!("fps must be in (0, {MAX_FPS}], got {}", settings.fps);
+        }
+        let _ = outbox.send(ServerEvent::Progress { id: id.clone(), stage: Stage::LoadingModel }.to_message()).await;
+        let options = settings.options.clone();
+        let captioner = tokio::task::spawn_blocking(move || {
+            let captioner = Captioner::load(options.model.as_deref(), options.quantized)?;
+            captioner.check_options(&options)?;
+            anyhow::Ok(captioner)
+        })
+        .await??;
+
+        let slot = Arc::new(FrameSlot::default());
+        let task = tokio::spawn(run_worker(id.clone(), settings, captioner, slot.clone(), outbox, hub, limits));
+        *live = Some(Self { id, slot, next_index: 0, task });
+        Ok(())
+    }
+
+    /// Replaces whatever frame is waiting with this one. The replaced frame is counted as dropped.
+    pub fn push_frame(&mut self, data: Vec<u8>) {
+        let frame = Frame { index: self.next_index, data };
+        self.next_index += 1;
+        if self.slot.frame.lock().unwrap().replace(frame).is_some() {
+            self.slot.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.slot.notify.notify_one();
+    }
+}
+
+impl Drop for LiveSession {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+enum FrameOutcome {
+    /// The frame looked like the previous one, so no caption was generated.
+    SameScene,
+    Captioned { caption: String, cls: Vec<f32> },
+}
+
+async fn run_worker(
+    id: String,
+    settings: LiveSettings,
+    mut captioner: Captioner,
+    slot: Arc<FrameSlot>,
+    outbox: mpsc::Sender<Message>,
+    hub: Arc<ChannelHub>,
+    limits: ImageLimits,
+) {
+    let publisher = hub.publisher("ws_live", settings.options.channel.as_deref());
+    let send = |event: ServerEvent| {
+        let outbox = outbox.clone();
+        async move { outbox.send(event.to_message()).await.is_ok() }
+    };
+
+    let period = Duration::from_secs_f64(1.0 / settings.fps);
+    let mut last_cls: Option<Vec<f32>> = None;
+    let mut last_caption: Option<String> = None;
+    loop {
+        slot.notify.notified().await;
+        let started = Instant::now();
+        let Some(frame) = slot.frame.lock().unwrap().take() else {
+            continue;
+        };
+
+        let options = settings.options.clone();
+        let threshold = settings.similarity_threshold;
+        let previous = last_cls.clone();
+        let joined = tokio::task::spawn_blocking(move || {
+            let outcome = caption_frame(&mut captioner, frame.data, &options, &limits, previous.as_deref(), threshold);
+            (captioner, outcome)
+        })
+        .await;
+        let outcome = match joined {
+            Ok((returned, outcome)) => {
+                captioner = returned;
+                outcome
+            }
+            Err(e) => {
+                send(ServerEvent::error(Some(&id), e)).await;
+                return;
+            }
+        };
+
+        match outcome {
+            Ok(FrameOutcome::SameScene) => {}
+            Ok(FrameOutcome::Captioned { caption, cls }) => {
+                last_cls = Some(cls);
+                if last_caption.as_deref() != Some(caption.as_str()) {
+                    let event = ServerEvent::LiveCaption {
+                        id: id.clone(),
+                        frame: frame.index,
+                        caption: caption.clone(),
+                        dropped: slot.dropped.swap(0, Ordering::Relaxed),
+                    };
+                    publisher.publish(&event);
+                    if !send(event).await {
+                        return;
+                    }
+                    last_caption = Some(caption);
+                }
+            }
+            Err(e) => {
+                if !send(ServerEvent::error(Some(&id), e)).await {
+                    return;
+                }
+            }
+        }
+
+        // hold off until the next slot so we never caption faster than the client asked for;
+        // frames arriving meanwhile just overwrite each other in the slot.
+        tokio::time::sleep_until(started + period).await;
+    }
+}
+
+fn caption_frame(
+    captioner: &mut Captioner,
+    data: Vec<u8>,
+    options: &CaptionOptions,
+    limits: &ImageLimits,
+    previous_cls: Option<&[f32]>,
+    threshold: f32,
+) -> anyhow::Result<FrameOutcome> {
+    let (image, _) = load_image(data, &options.preprocess, captioner.image_spec(), limits)?;
+    let image_embeds = captioner.encode(&image)?;
+    let cls = image_embeds.i((0, 0))?.to_vec1::<f32>()?;
+    if let Some(previous) = previous_cls {
+        if cosine_similarity(previous, &cls) >= threshold {
+            return Ok(FrameOutcome::SameScene);
+        }
+    }
+    let caption = captioner.generate(&image_embeds, options, |_| Ok(()))?;
+    Ok(FrameOutcome::Captioned { caption, cls })
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_args() -> (mpsc::Sender<Message>, Arc<ChannelHub>, ImageLimits) {
+        let (outbox, _) = mpsc::channel(16);
+        (outbox, Arc::new(ChannelHub::new(16)), ImageLimits::default())
+    }
+
+    fn idle_session(id: &str) -> LiveSession {
+        let task = tokio::spawn(std::future::pending());
+        LiveSession { id: id.to_string(), slot: Arc::new(FrameSlot::default()), next_index: 0, task }
+    }
+
+    #[tokio::test]
+    async fn only_the_newest_frame_is_kept() {
+        let mut session = idle_session("a");
+        for data in [vec![1], vec![2], vec![3]] {
+            session.push_frame(data);
+        }
+        let frame = session.slot.frame.lock().unwrap().take().unwrap();
+        assert_eq!((frame.index, frame.data), (2, vec![3]));
+        assert_eq!(session.slot.dropped.swap(0, Ordering::Relaxed), 2);
+
+        // once the worker took a frame the next one replaces nothing
+        session.push_frame(vec![4]);
+        assert_eq!(session.slot.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn cosine_similarity_of_embeddings() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+        // a zero embedding is similar to nothing, rather than NaN
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 2.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[0.0, 0.0]), 0.0);
+    }
+
+    #[tokio::test]
+    async fn failed_starts_leave_no_session_behind() {
+        let mut live = None;
+        let settings = LiveSettings {
+            options: CaptionOptions { model: Some("blip-huge".to_string()), ..CaptionOptions::default() },
+            ..LiveSettings::default()
+        };
+        let (outbox, hub, limits) = start_args();
+        let error = LiveSession::start(&mut live, "a".to_string(), settings, outbox, hub, limits).await.unwrap_err();
+        assert!(error.to_string().contains("unknown model"), "{error}");
+        assert!(live.is_none());
+
+        // the next start is judged on its own settings, not turned away as already running
+        let settings = LiveSettings { fps: 0.0, ..LiveSettings::default() };
+        let (outbox, hub, limits) = start_args();
+        let error = LiveSession::start(&mut live, "a".to_string(), settings, outbox, hub, limits).await.unwrap_err();
+        assert!(error.to_string().contains("fps must be in"), "{error}");
+        assert!(live.is_none());
+    }
+
+    #[tokio::test]
+    async fn one_session_per_connection() {
+        let mut live = Some(idle_session("a"));
+        let (outbox, hub, limits) = start_args();
+        let error = LiveSession::start(&mut live, "b".to_string(), LiveSettings::default(), outbox, hub, limits).await.unwrap_err();
+        assert_eq!(error.to_string(), "live session a is already running");
+        assert_eq!(live.unwrap().id, "a");
+    }
+}
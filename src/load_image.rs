@@ -1,23 +1,693 @@
 use candle_core::{Device, DType, Tensor};
-use tokio_util::io::StreamReader;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
 use std::io::Cursor;
+use crate::svg;
 
-/// Loads an image from disk using the image crate, this returns a tensor with shape
-/// (3, 384, 384). OpenAI normalization is applied.
-pub fn load_image(p: axum::body::Bytes) -> candle_core::Result<Tensor> {
-    let img = image::ImageReader::new(Cursor::new(p))
-        .with_guessed_format()?
-        .decode()
-        .map_err(candle_core::Error::wrap)?
-        .resize_to_fill(384, 384, image::imageops::FilterType::Triangle);
-    let img = img.to_rgb8();
+/// Pixels with an alpha below this count as transparent when measuring transparency.
+const TRANSPARENT_ALPHA: u8 = 16;
+
+/// Share of transparent pixels above which an image is reported as mostly transparent.
+const MOSTLY_TRANSPARENT: f32 = 0.5;
+
+/// Default length of the longest side SVG uploads are rasterized at.
+const SVG_SIZE: u32 = 768;
+
+/// The input a vision encoder expects: a square of `size` pixels, normalized per channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageSpec {
+    pub size: u32,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+impl ImageSpec {
+    /// 384 pixel squares with the OpenAI CLIP normalization, the transformers defaults for a BLIP
+    /// preprocessor config that leaves them out.
+    pub const BLIP: Self = Self {
+        size: 384,
+        mean: [0.48145466, 0.4578275, 0.40821073],
+        std: [0.26862954, 0.261_302_6, 0.275_777_1],
+    };
+}
+
+/// How an image is fitted into the square model input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeMode {
+    /// Scale to cover the square and crop the overflow, losing the edges of long images.
+    #[default]
+    Crop,
+    /// Scale to fit inside the square and pad the rest with `pad_color`.
+    Letterbox,
+    /// Scale each axis independently, distorting the aspect ratio.
+    Stretch,
+}
+
+/// Resampling filter used when resizing, mirroring `image::imageops::FilterType`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    Nearest,
+    #[default]
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// An RGB color written as `#rrggbb` in requests and responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value.strip_prefix('#').unwrap_or(&value);
+        let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Self([r, g, b])),
+            _ => Err(format!("invalid color `{value}`, expected #rrggbb")),
+        }
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        let [r, g, b] = color.0;
+        format!("#{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+/// Per-request image preprocessing knobs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreprocessOptions {
+    pub resize: ResizeMode,
+    /// Fill for the bars added by `letterbox`.
+    pub pad_color: Color,
+    pub filter: ResizeFilter,
+    /// Color transparent images are composited onto, so they are not captioned as black shapes.
+    pub background: Color,
+    /// Measure how much of an image with alpha is transparent and report it.
+    pub detect_transparency: bool,
+    /// Length in pixels of the longest side SVG uploads are rasterized at.
+    pub svg_size: u32,
+}
+
+impl Default for PreprocessOptions {
+    fn default() -> Self {
+        Self {
+            resize: ResizeMode::Crop,
+            pad_color: Color([0, 0, 0]),
+            filter: ResizeFilter::Triangle,
+            background: Color([255, 255, 255]),
+            detect_transparency: false,
+            svg_size: SVG_SIZE,
+        }
+    }
+}
+
+/// EXIF orientation of an upload, i.e. the transform that turns the stored pixels upright.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    Normal,
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    fn from_exif(value: u32) -> Self {
+        match value {
+            2 => Self::FlipHorizontal,
+            3 => Self::Rotate180,
+            4 => Self::FlipVertical,
+            5 => Self::Transpose,
+            6 => Self::Rotate90,
+            7 => Self::Transverse,
+            8 => Self::Rotate270,
+            _ => Self::Normal,
+        }
+    }
+
+    /// Reads the orientation tag, treating images without (readable) EXIF data as upright.
+    fn read(data: &[u8]) -> Self {
+        let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(data)) else {
+            return Self::Normal;
+        };
+        exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .map_or(Self::Normal, Self::from_exif)
+    }
+
+    fn apply(self, img: DynamicImage) -> DynamicImage {
+        match self {
+            Self::Normal => img,
+            Self::FlipHorizontal => img.fliph(),
+            Self::Rotate180 => img.rotate180(),
+            Self::FlipVertical => img.flipv(),
+            Self::Transpose => img.rotate90().fliph(),
+            Self::Rotate90 => img.rotate90(),
+            Self::Transverse => img.rotate270().fliph(),
+            Self::Rotate270 => img.rotate270(),
+        }
+    }
+}
+
+/// What preprocessing found out about an upload, reported back alongside the caption.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageInfo {
+    /// Size after the orientation was applied.
+    pub width: u32,
+    pub height: u32,
+    pub orientation: Orientation,
+    pub resize: ResizeMode,
+    pub filter: ResizeFilter,
+    /// Fill of the bars around the image, only set for `letterbox`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pad_color: Option<Color>,
+    /// Set when the image had an alpha channel and was flattened onto `background`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<Color>,
+    /// Only reported when `detect_transparency` was requested and the image has alpha.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transparency: Option<Transparency>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transparency {
+    /// Share of pixels that are (almost) fully transparent.
+    pub fraction: f32,
+    pub mostly_transparent: bool,
+}
+
+fn measure_transparency(img: &image::RgbaImage) -> Transparency {
+    let total = (img.width() as usize * img.height() as usize).max(1);
+    let transparent = img.pixels().filter(|p| p.0[3] < TRANSPARENT_ALPHA).count();
+    let fraction = transparent as f32 / total as f32;
+    Transparency {
+        fraction,
+        mostly_transparent: fraction > MOSTLY_TRANSPARENT,
+    }
+}
+
+/// Blends every pixel over `background` by its alpha, dropping the alpha channel.
+pub fn composite(img: &image::RgbaImage, background: Color) -> RgbImage {
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        let alpha = a as u32;
+        let blend = |c: u8, bg: u8| ((c as u32 * alpha + bg as u32 * (255 - alpha) + 127) / 255) as u8;
+        let [br, bg, bb] = background.0;
+        Rgb([blend(r, br), blend(g, bg), blend(b, bb)])
+    })
+}
+
+/// Server-wide caps on what an upload may decode to, so a small file that declares huge dimensions
+/// is rejected before any pixel memory is allocated.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    pub max_decoded_bytes: u64,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 100_000_000,
+            max_decoded_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl ImageLimits {
+    pub fn check(&self, width: u32, height: u32, decoded_bytes: u64) -> Result<(), PreprocessError> {
+        let too_large = |reason: String| Err(PreprocessError::TooLarge { width, height, reason });
+        let pixels = width as u64 * height as u64;
+        if width > self.max_width {
+            return too_large(format!("width exceeds {}", self.max_width));
+        }
+        if height > self.max_height {
+            return too_large(format!("height exceeds {}", self.max_height));
+        }
+        if pixels > self.max_pixels {
+            return too_large(format!("{pixels} pixels exceed {}", self.max_pixels));
+        }
+        if decoded_bytes > self.max_decoded_bytes {
+            return too_large(format!("{decoded_bytes} decoded bytes exceed {}", self.max_decoded_bytes));
+        }
+        Ok(())
+    }
+
+    pub fn to_decoder_limits(self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_decoded_bytes);
+        limits
+    }
+}
+
+/// Why an upload could not be turned into model input.
+#[derive(Debug)]
+pub enum PreprocessError {
+    /// The image is over one of the [`ImageLimits`]; `width` and `height` are what it declared.
+    TooLarge { width: u32, height: u32, reason: String },
+    /// The bytes are not an image we can decode.
+    Invalid(String),
+    /// The image is fine but the options sent with it are not, e.g. an unknown model or a value
+    /// out of range.
+    InvalidRequest(String),
+    /// A request referenced an earlier upload by `image_id` that cannot be used, e.g. because its
+    /// embeddings have been evicted.
+    NotCached { image_id: String, reason: &'static str },
+}
+
+impl std::fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge { width, height, reason } => {
+                write!(f, "image of {width}x{height} is too large: {reason}")
+            }
+            Self::Invalid(message) => write!(f, "invalid image: {message}"),
+            Self::InvalidRequest(message) => write!(f, "invalid request: {message}"),
+            Self::NotCached { image_id, reason } => {
+                write!(f, "image {image_id} is not available: {reason}, upload it again")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreprocessError {}
+
+/// Decodes an upload within `limits`. The header is read first so oversized images are rejected
+/// with their declared size; the decoder then enforces the same limits on its own allocations.
+fn decode_within_limits(p: &[u8], limits: &ImageLimits) -> Result<DynamicImage, PreprocessError> {
+    let invalid = |e: std::io::Error| PreprocessError::Invalid(e.to_string());
+    let mut probe = image::ImageReader::new(Cursor::new(p)).with_guessed_format().map_err(invalid)?;
+    probe.no_limits();
+    let decoder = probe.into_decoder().map_err(|e| PreprocessError::Invalid(e.to_string()))?;
+    let (width, height) = decoder.dimensions();
+    limits.check(width, height, decoder.total_bytes())?;
+
+    let mut reader = image::ImageReader::new(Cursor::new(p)).with_guessed_format().map_err(invalid)?;
+    reader.limits(limits.to_decoder_limits());
+    reader.decode().map_err(|e| match e {
+        image::ImageError::Limits(e) => PreprocessError::TooLarge { width, height, reason: e.to_string() },
+        e => PreprocessError::Invalid(e.to_string()),
+    })
+}
+
+/// Decodes an upload, turns it upright according to its EXIF orientation and flattens any
+/// transparency onto the configured background. SVG uploads are rasterized first.
+pub fn decode_image(p: &[u8], options: &PreprocessOptions, limits: &ImageLimits) -> anyhow::Result<(DynamicImage, ImageInfo)> {
+    let (img, orientation) = if svg::is_svg(p) {
+        (DynamicImage::ImageRgba8(svg::rasterize(p, options.svg_size, limits)?), Orientation::Normal)
+    } else {
+        (decode_within_limits(p, limits)?, Orientation::read(p))
+    };
+    let mut img = orientation.apply(img);
+    let mut info = ImageInfo {
+        width: img.width(),
+        height: img.height(),
+        orientation,
+        resize: options.resize,
+        filter: options.filter,
+        pad_color: (options.resize == ResizeMode::Letterbox).then_some(options.pad_color),
+        background: None,
+        transparency: None,
+    };
+    if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        if options.detect_transparency {
+            info.transparency = Some(measure_transparency(&rgba));
+        }
+        img = DynamicImage::ImageRgb8(composite(&rgba, options.background));
+        info.background = Some(options.background);
+    }
+    Ok((img, info))
+}
+
+/// Fits a decoded image into the model's square input of `size` pixels according to
+/// `options.resize`.
+fn resize(img: &DynamicImage, options: &PreprocessOptions, size: u32) -> RgbImage {
+    let filter = options.filter.into();
+    match options.resize {
+        ResizeMode::Crop => img.resize_to_fill(size, size, filter).to_rgb8(),
+        ResizeMode::Stretch => img.resize_exact(size, size, filter).to_rgb8(),
+        ResizeMode::Letterbox => {
+            let fitted = img.resize(size, size, filter).to_rgb8();
+            let mut canvas = RgbImage::from_pixel(size, size, Rgb(options.pad_color.0));
+            let x = (size - fitted.width()) / 2;
+            let y = (size - fitted.height()) / 2;
+            image::imageops::overlay(&mut canvas, &fitted, x.into(), y.into());
+            canvas
+        }
+    }
+}
+
+/// Resizes a decoded image to (3, size, size) and normalizes it as `spec` says.
+pub fn image_to_tensor(img: &DynamicImage, options: &PreprocessOptions, spec: &ImageSpec) -> candle_core::Result<Tensor> {
+    let size = spec.size as usize;
+    let img = resize(img, options, spec.size);
     let data = img.into_raw();
-    let data = Tensor::from_vec(data, (384, 384, 3), &Device::Cpu)?.permute((2, 0, 1))?;
-    let mean =
-        Tensor::new(&[0.48145466f32, 0.4578275, 0.40821073], &Device::Cpu)?.reshape((3, 1, 1))?;
-    let std = Tensor::new(&[0.26862954f32, 0.261_302_6, 0.275_777_1], &Device::Cpu)?
-        .reshape((3, 1, 1))?;
+    let data = Tensor::from_vec(data, (size, size, 3), &Device::Cpu)?.permute((2, 0, 1))?;
+    let mean = Tensor::new(&spec.mean, &Device::Cpu)?.reshape((3, 1, 1))?;
+    let std = Tensor::new(&spec.std, &Device::Cpu)?.reshape((3, 1, 1))?;
     (data.to_dtype(DType::F32)? / 255.)?
         .broadcast_sub(&mean)?
         .broadcast_div(&std)
-}
\ No newline at end of file
+}
+
+/// Loads an image from memory using the image crate, this returns a tensor preprocessed for
+/// `spec` and what was learned while decoding it.
+pub fn load_image(p: impl AsRef<[u8]>, options: &PreprocessOptions, spec: &ImageSpec, limits: &ImageLimits) -> anyhow::Result<(Tensor, ImageInfo)> {
+    let (img, info) = decode_image(p.as_ref(), options, limits)?;
+    Ok((image_to_tensor(&img, options, spec)?, info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIENTATIONS: [Orientation; 8] = [
+        Orientation::Normal,
+        Orientation::FlipHorizontal,
+        Orientation::Rotate180,
+        Orientation::FlipVertical,
+        Orientation::Transpose,
+        Orientation::Rotate90,
+        Orientation::Transverse,
+        Orientation::Rotate270,
+    ];
+
+    /// A 3x2 image, black except for a red top left and a green second pixel, so every
+    /// orientation moves the two marked pixels somewhere else.
+    fn marked() -> RgbImage {
+        let mut img = RgbImage::new(3, 2);
+        img.put_pixel(0, 0, Rgb([255, 0, 0]));
+        img.put_pixel(1, 0, Rgb([0, 255, 0]));
+        img
+    }
+
+    fn find(img: &RgbImage, color: Rgb<u8>) -> (u32, u32) {
+        img.enumerate_pixels().find(|(_, _, p)| **p == color).map(|(x, y, _)| (x, y)).unwrap()
+    }
+
+    fn png_with_orientation(img: &RgbImage, orientation: u16) -> Vec<u8> {
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        // a big endian TIFF header with one IFD holding the orientation tag
+        let mut exif = b"MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        let mut chunk = (exif.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"eXIf");
+        chunk.extend_from_slice(&exif);
+        chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+        // right after the signature and IHDR
+        png.splice(33..33, chunk);
+        png
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        !data.iter().fold(!0u32, |crc, &byte| {
+            (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+        })
+    }
+
+    /// A PNG that is nothing but a header declaring `width` by `height` pixels, followed by an
+    /// empty data chunk so decoders get past the header.
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let mut ihdr = b"IHDR".to_vec();
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        // 8 bit RGB, default compression, filter and interlacing
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend_from_slice(&13u32.to_be_bytes());
+        png.extend_from_slice(&ihdr);
+        png.extend_from_slice(&crc32(&ihdr).to_be_bytes());
+        for name in [b"IDAT", b"IEND"] {
+            png.extend_from_slice(&0u32.to_be_bytes());
+            png.extend_from_slice(name);
+            png.extend_from_slice(&crc32(name).to_be_bytes());
+        }
+        png
+    }
+
+    /// A GIF whose logical screen is `width` by `height` pixels, holding a single 1x1 frame.
+    fn gif_header(width: u16, height: u16) -> Vec<u8> {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&width.to_le_bytes());
+        gif.extend_from_slice(&height.to_le_bytes());
+        // a two color global palette
+        gif.extend_from_slice(&[0x80, 0, 0, 0, 0, 0, 255, 255, 255]);
+        gif.extend_from_slice(&[b',', 0, 0, 0, 0, 1, 0, 1, 0, 0]);
+        gif.extend_from_slice(&[2, 2, 0x44, 0x01, 0, b';']);
+        gif
+    }
+
+    fn too_large(data: &[u8], limits: &ImageLimits) -> (u32, u32, String) {
+        match decode_within_limits(data, limits) {
+            Err(e @ PreprocessError::TooLarge { width, height, .. }) => (width, height, e.to_string()),
+            other => panic!("expected TooLarge, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn huge_declared_sizes_are_rejected_from_the_header() {
+        let (width, height, message) = too_large(&png_header(100_000, 100_000), &ImageLimits::default());
+        assert_eq!((width, height), (100_000, 100_000));
+        assert!(message.contains("100000x100000"), "{message}");
+
+        let (width, height, message) = too_large(&gif_header(60_000, 60_000), &ImageLimits::default());
+        assert_eq!((width, height), (60_000, 60_000));
+        assert!(message.contains("60000x60000"), "{message}");
+    }
+
+    #[test]
+    fn every_limit_is_checked() {
+        let limits = ImageLimits { max_width: 1000, max_height: 1000, max_pixels: 10_000, max_decoded_bytes: 3_000 };
+        for ((width, height), reason) in [
+            ((1001, 1), "width exceeds 1000"),
+            ((1, 1001), "height exceeds 1000"),
+            ((200, 200), "40000 pixels exceed 10000"),
+            ((50, 50), "7500 decoded bytes exceed 3000"),
+        ] {
+            let (_, _, message) = too_large(&png_header(width, height), &limits);
+            assert!(message.contains(&format!("{width}x{height}")), "{message}");
+            assert!(message.ends_with(reason), "{message}");
+        }
+    }
+
+    #[test]
+    fn garbage_is_invalid_not_too_large() {
+        let result = decode_within_limits(b"definitely not an image", &ImageLimits::default());
+        assert!(matches!(result, Err(PreprocessError::Invalid(_))));
+    }
+
+    #[test]
+    fn exif_values_map_to_orientations() {
+        for (value, orientation) in (1..=8).zip(ORIENTATIONS) {
+            assert_eq!(Orientation::from_exif(value), orientation);
+        }
+        assert_eq!(Orientation::from_exif(0), Orientation::Normal);
+        assert_eq!(Orientation::from_exif(9), Orientation::Normal);
+    }
+
+    #[test]
+    fn orientations_turn_the_stored_pixels_upright() {
+        // where the red and green pixels end up, and the upright size
+        let expected = [
+            ((0, 0), (1, 0), (3, 2)),
+            ((2, 0), (1, 0), (3, 2)),
+            ((2, 1), (1, 1), (3, 2)),
+            ((0, 1), (1, 1), (3, 2)),
+            ((0, 0), (0, 1), (2, 3)),
+            ((1, 0), (1, 1), (2, 3)),
+            ((1, 2), (1, 1), (2, 3)),
+            ((0, 2), (0, 1), (2, 3)),
+        ];
+        for (orientation, (red, green, size)) in ORIENTATIONS.into_iter().zip(expected) {
+            let upright = orientation.apply(DynamicImage::ImageRgb8(marked())).to_rgb8();
+            assert_eq!(upright.dimensions(), size, "{orientation:?}");
+            assert_eq!(find(&upright, Rgb([255, 0, 0])), red, "{orientation:?}");
+            assert_eq!(find(&upright, Rgb([0, 255, 0])), green, "{orientation:?}");
+        }
+    }
+
+    #[test]
+    fn decoding_applies_and_reports_the_exif_orientation() {
+        let png = png_with_orientation(&marked(), 6);
+        let (img, info) = decode_image(&png, &PreprocessOptions::default(), &ImageLimits::default()).unwrap();
+        assert_eq!(info.orientation, Orientation::Rotate90);
+        assert_eq!((info.width, info.height), (2, 3));
+        assert_eq!(find(&img.to_rgb8(), Rgb([255, 0, 0])), (1, 0));
+    }
+
+    #[test]
+    fn images_without_exif_are_upright() {
+        let mut png = Vec::new();
+        marked().write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        let (_, info) = decode_image(&png, &PreprocessOptions::default(), &ImageLimits::default()).unwrap();
+        assert_eq!(info.orientation, Orientation::Normal);
+        assert_eq!((info.width, info.height), (3, 2));
+    }
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const GREEN: Rgb<u8> = Rgb([0, 255, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+    /// An 8x4 image, red in its outer two columns on either side and green in the middle four.
+    fn wide() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(8, 4, |x, _| if (2..6).contains(&x) { GREEN } else { RED }))
+    }
+
+    fn fitted(img: &DynamicImage, mode: ResizeMode, filter: ResizeFilter) -> RgbImage {
+        let options = PreprocessOptions { resize: mode, filter, pad_color: Color(BLUE.0), ..PreprocessOptions::default() };
+        resize(img, &options, 4)
+    }
+
+    #[test]
+    fn letterbox_pads_the_short_side() {
+        let img = fitted(&wide(), ResizeMode::Letterbox, ResizeFilter::Nearest);
+        assert_eq!(img.dimensions(), (4, 4));
+        for x in 0..4 {
+            // the 4x2 image sits in the middle rows with a bar above and below it
+            assert_eq!(*img.get_pixel(x, 0), BLUE);
+            assert_eq!(*img.get_pixel(x, 3), BLUE);
+            assert_ne!(*img.get_pixel(x, 1), BLUE);
+            assert_ne!(*img.get_pixel(x, 2), BLUE);
+        }
+        assert_eq!(*img.get_pixel(0, 1), RED);
+        assert_eq!(*img.get_pixel(1, 2), GREEN);
+        assert_eq!(*img.get_pixel(3, 1), RED);
+    }
+
+    #[test]
+    fn stretch_keeps_everything_and_distorts() {
+        let img = fitted(&wide(), ResizeMode::Stretch, ResizeFilter::Nearest);
+        assert_eq!(img.dimensions(), (4, 4));
+        for y in 0..4 {
+            assert_eq!(*img.get_pixel(0, y), RED);
+            assert_eq!(*img.get_pixel(1, y), GREEN);
+            assert_eq!(*img.get_pixel(2, y), GREEN);
+            assert_eq!(*img.get_pixel(3, y), RED);
+        }
+    }
+
+    #[test]
+    fn crop_cuts_off_the_long_side() {
+        let img = fitted(&wide(), ResizeMode::Crop, ResizeFilter::Nearest);
+        assert_eq!(img.dimensions(), (4, 4));
+        assert!(img.pixels().all(|p| *p == GREEN));
+    }
+
+    #[test]
+    fn the_filter_decides_how_pixels_are_resampled() {
+        let stripes = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, _| if x % 2 == 0 { Rgb([0; 3]) } else { Rgb([255; 3]) }));
+        let sharp = |img: &RgbImage| img.pixels().all(|p| p.0[0] == 0 || p.0[0] == 255);
+        assert!(sharp(&fitted(&stripes, ResizeMode::Stretch, ResizeFilter::Nearest)));
+        assert!(!sharp(&fitted(&stripes, ResizeMode::Stretch, ResizeFilter::Triangle)));
+    }
+
+    #[test]
+    fn only_letterboxed_images_report_a_pad_color() {
+        let mut png = Vec::new();
+        wide().write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        let letterbox = PreprocessOptions { resize: ResizeMode::Letterbox, pad_color: Color(BLUE.0), ..PreprocessOptions::default() };
+        let (_, info) = decode_image(&png, &letterbox, &ImageLimits::default()).unwrap();
+        assert_eq!(info.pad_color, Some(Color(BLUE.0)));
+        let (_, info) = decode_image(&png, &PreprocessOptions::default(), &ImageLimits::default()).unwrap();
+        assert_eq!(info.pad_color, None);
+    }
+
+    /// A 4x1 image whose pixels have the given alphas.
+    fn with_alphas(alphas: [u8; 4]) -> image::RgbaImage {
+        image::RgbaImage::from_fn(4, 1, |x, _| image::Rgba([200, 100, 50, alphas[x as usize]]))
+    }
+
+    #[test]
+    fn transparent_images_are_flattened_onto_white_by_default() {
+        let mut png = Vec::new();
+        with_alphas([0; 4]).write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        let (img, info) = decode_image(&png, &PreprocessOptions::default(), &ImageLimits::default()).unwrap();
+        assert!(!img.color().has_alpha());
+        assert!(img.to_rgb8().pixels().all(|p| *p == Rgb([255, 255, 255])));
+        assert_eq!(info.background, Some(Color([255, 255, 255])));
+        // nobody asked about transparency
+        assert!(info.transparency.is_none());
+    }
+
+    #[test]
+    fn composite_blends_onto_the_background_by_alpha() {
+        let img = composite(&with_alphas([0, 255, 128, 0]), Color([10, 20, 30]));
+        assert_eq!(*img.get_pixel(0, 0), Rgb([10, 20, 30]));
+        assert_eq!(*img.get_pixel(1, 0), Rgb([200, 100, 50]));
+        assert_eq!(*img.get_pixel(2, 0), Rgb([105, 60, 40]));
+    }
+
+    #[test]
+    fn opaque_images_pass_through_unchanged() {
+        let opaque = with_alphas([255; 4]);
+        let img = composite(&opaque, Color([10, 20, 30]));
+        assert!(img.pixels().all(|p| *p == Rgb([200, 100, 50])));
+
+        let mut png = Vec::new();
+        marked().write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        let options = PreprocessOptions { detect_transparency: true, ..PreprocessOptions::default() };
+        let (img, info) = decode_image(&png, &options, &ImageLimits::default()).unwrap();
+        assert_eq!(img.to_rgb8(), marked());
+        assert!(info.background.is_none());
+        assert!(info.transparency.is_none());
+    }
+
+    #[test]
+    fn mostly_transparent_means_more_than_half() {
+        let half = measure_transparency(&with_alphas([0, 0, 255, 255]));
+        assert_eq!(half.fraction, 0.5);
+        assert!(!half.mostly_transparent);
+        let most = measure_transparency(&with_alphas([0, 0, 0, 255]));
+        assert_eq!(most.fraction, 0.75);
+        assert!(most.mostly_transparent);
+        // pixels count as transparent below an alpha of 16
+        let faint = measure_transparency(&with_alphas([15, 15, 16, 16]));
+        assert_eq!(faint.fraction, 0.5);
+    }
+
+    #[test]
+    fn transparency_is_reported_when_asked_for() {
+        let mut png = Vec::new();
+        with_alphas([0, 0, 0, 255]).write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        let options = PreprocessOptions { detect_transparency: true, ..PreprocessOptions::default() };
+        let (_, info) = decode_image(&png, &options, &ImageLimits::default()).unwrap();
+        let transparency = info.transparency.unwrap();
+        assert_eq!(transparency.fraction, 0.75);
+        assert!(transparency.mostly_transparent);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashMap};
+
+struct Entry<V> {
+    used_at: u64,
+    weight: usize,
+    value: V,
+}
+
+/// A least recently used map that evicts its oldest entries once the summed weight of the values
+/// goes over `budget`. What a weight means is up to the owner, e.g. one per entry or bytes.
+pub struct Lru<V> {
+    budget: usize,
+    used: usize,
+    entries: HashMap<String, Entry<V>>,
+    /// Last use of every entry, oldest first.
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl<V> Lru<V> {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Looks up `key` and marks it as the most recently used entry.
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.used_at);
+        entry.used_at = self.clock;
+        self.recency.insert(self.clock, key.to_string());
+        Some(&entry.value)
+    }
+
+    /// Stores `value`, evicting least recently used entries until it fits, and returns the keys it
+    /// evicted. Values heavier than the whole budget are not stored at all and come back as
+    /// evicted themselves.
+    pub fn insert(&mut self, key: &str, value: V, weight: usize) -> Vec<String> {
+        self.remove(key);
+        if weight > self.budget {
+            return vec![key.to_string()];
+        }
+        self.clock += 1;
+        self.used += weight;
+        self.entries.insert(key.to_string(), Entry { used_at: self.clock, weight, value });
+        self.recency.insert(self.clock, key.to_string());
+        let mut evicted = Vec::new();
+        while self.used > self.budget {
+            let Some((_, oldest)) = self.recency.pop_first() else { break };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.used -= entry.weight;
+                evicted.push(oldest);
+            }
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.used_at);
+        self.used -= entry.weight;
+        Some(entry.value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.used = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Summed weight of everything stored.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1, 1);
+        lru.insert("b", 2, 1);
+        assert_eq!(lru.get("a"), Some(&1));
+        lru.insert("c", 3, 1);
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(&1));
+        assert_eq!(lru.get("c"), Some(&3));
+    }
+
+    #[test]
+    fn reports_what_it_evicted() {
+        let mut lru = Lru::new(2);
+        assert!(lru.insert("a", (), 1).is_empty());
+        assert!(lru.insert("b", (), 1).is_empty());
+        assert_eq!(lru.insert("c", (), 2), vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(lru.insert("big", (), 3), vec!["big".to_string()]);
+    }
+
+    #[test]
+    fn evicts_by_weight() {
+        let mut lru = Lru::new(10);
+        lru.insert("a", (), 4);
+        lru.insert("b", (), 4);
+        lru.insert("c", (), 5);
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.used(), 9);
+        assert!(lru.get("a").is_none());
+    }
+
+    #[test]
+    fn values_heavier_than_the_budget_are_not_stored() {
+        let mut lru = Lru::new(10);
+        lru.insert("a", (), 4);
+        lru.insert("big", (), 11);
+        assert!(lru.get("big").is_none());
+        assert_eq!(lru.used(), 4);
+    }
+
+    #[test]
+    fn reinserting_replaces_the_weight() {
+        let mut lru = Lru::new(10);
+        lru.insert("a", 1, 4);
+        lru.insert("a", 2, 6);
+        assert_eq!(lru.len(), 1);
+        assert_eq!(lru.used(), 6);
+        assert_eq!(lru.remove("a"), Some(2));
+        assert_eq!(lru.used(), 0);
+    }
+}
@@ -1,42 +1,336 @@
 mod token_output_stream;
+mod animation;
+mod api_error;
+mod backend;
+mod blip_config;
+mod blip_itm;
+mod blip_text_encoder;
+mod blip_vision;
+mod blip_vqa;
+mod caption_cache;
+mod captioner;
+mod channels;
+mod chunked_upload;
+mod embed;
+mod embedding_cache;
+mod idle_timer;
+mod in_flight;
+mod live;
+mod lru;
 mod load_image;
+mod models;
+mod options;
+mod regions;
+mod rerank;
 mod run_blip;
 mod run_blip_ws;
+mod search_index;
+mod svg;
+mod video;
+mod ws_protocol;
 
 use std::borrow::Cow;
-use std::io;
+use std::collections::HashMap;
+use std::io::{self, Write};
 use std::net::SocketAddr;
 use std::ops::ControlFlow;
-use clap::Parser;
-use crate::run_blip::run_blip;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use clap::{Parser, Subcommand};
+use crate::run_blip::{run_blip, Caches, CachesStats};
 use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 use tower_http::cors::CorsLayer;
 
-use axum::{routing::{get, post}, http::StatusCode, Router, ServiceExt};
-use axum::extract::{ConnectInfo, DefaultBodyLimit, Multipart};
-use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::{routing::{get, post}, http::{header, HeaderMap, StatusCode}, Json, Router, ServiceExt};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path as AxumPath, Query, State};
+use axum::body::Bytes;
+use axum::extract::multipart::MultipartError;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
 use axum_extra::TypedHeader;
 use axum::response::{Html, IntoResponse, Response};
 use futures::{SinkExt, Stream, StreamExt, TryStreamExt};
-use futures::stream::SplitSink;
+use futures::stream::SplitStream;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Interval};
+use crate::api_error::ApiError;
+use crate::blip_itm::{match_texts, MatchOptions, MatchResult, MAX_MATCH_TEXTS};
+use crate::blip_vqa::{answer_question, VqaOptions, VqaResult};
+use crate::caption_cache::CaptionCache;
+use crate::embed::{embed_image, EmbedOptions, EmbeddingFormat, Pooling};
+use crate::embedding_cache::EmbeddingCache;
+use crate::idle_timer::IdleTimer;
+use crate::in_flight::InFlight;
+use crate::channels::{ChannelHub, Subscription};
+use crate::chunked_upload::ChunkedUpload;
+use crate::live::LiveSession;
+use crate::load_image::ImageLimits;
+use crate::models::{ModelListing, RegistryConfig};
+use crate::options::{CaptionOptions, CaptionResult};
 use crate::run_blip_ws::run_blip_ws;
+use crate::search_index::{SearchHit, SearchIndex};
+use crate::video::{caption_video, render_cues, SubtitleFormat, VideoOptions};
+use crate::ws_protocol::{decode_inline_image, parse_client_message, ClientMessage, ServerEvent};
+
+/// BLIP captioning server with an HTTP upload form and a streaming WebSocket API.
+#[derive(Parser, Debug, Clone)]
+struct Args {
+    /// Seconds between WebSocket heartbeat pings, 0 disables them.
+    #[arg(long, default_value_t = 30)]
+    heartbeat_secs: u64,
+
+    /// Close WebSocket connections that sent no request and have nothing in flight for this many
+    /// seconds, 0 disables the timeout.
+    #[arg(long, default_value_t = 300)]
+    idle_timeout_secs: u64,
+
+    /// How many captions one WebSocket connection may have running at the same time.
+    #[arg(long, default_value_t = 4)]
+    max_in_flight: usize,
+
+    /// Largest image, in bytes, a client may send inline or announce for a chunked WebSocket
+    /// upload.
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    max_upload_bytes: usize,
+
+    /// How many events a caption channel buffers for a slow subscriber before it lags.
+    #[arg(long, default_value_t = 256)]
+    channel_capacity: usize,
+
+    /// Reject images wider than this many pixels.
+    #[arg(long, default_value_t = ImageLimits::default().max_width)]
+    max_image_width: u32,
+
+    /// Reject images taller than this many pixels.
+    #[arg(long, default_value_t = ImageLimits::default().max_height)]
+    max_image_height: u32,
+
+    /// Reject images with more pixels than this in total.
+    #[arg(long, default_value_t = ImageLimits::default().max_pixels)]
+    max_image_pixels: u64,
+
+    /// Reject images whose decoded pixel buffer would exceed this many bytes.
+    #[arg(long, default_value_t = ImageLimits::default().max_decoded_bytes)]
+    max_decoded_bytes: u64,
+
+    /// How many finished captions the in-memory cache keeps, 0 disables it.
+    #[arg(long, default_value_t = 1024)]
+    cache_entries: usize,
+
+    /// Also keep cached captions in this directory, so they survive restarts.
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Largest size, in bytes, the on-disk caption cache may grow to.
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    cache_disk_bytes: u64,
+
+    /// Memory budget, in bytes, for cached vision embeddings that let follow-up requests skip
+    /// the vision encoder, 0 disables the cache.
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    embedding_cache_bytes: usize,
+
+    /// Keep the search index in this file, so it survives restarts.
+    #[arg(long)]
+    index_path: Option<PathBuf>,
+
+    /// Bearer token required by the `/admin` endpoints. Without it they are not served at all.
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// JSON file listing the models to serve, see `models::RegistryConfig`. Without it BLIP large
+    /// is served in full precision and as q4k.
+    #[arg(long)]
+    models: Option<PathBuf>,
+
+    /// The ffmpeg binary used to pull frames out of videos.
+    #[arg(long, default_value = "ffmpeg")]
+    ffmpeg: PathBuf,
+
+    /// Run a one-off command instead of starting the server.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Caption a local video file and print the subtitles to stdout.
+    Video {
+        path: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = SubtitleFormat::Vtt)]
+        format: SubtitleFormat,
+
+        /// Caption one frame every this many seconds.
+        #[arg(long, default_value_t = 2.0)]
+        interval_secs: f64,
+
+        /// Caption scene changes scoring above this threshold (0 to 1) instead of sampling at an interval.
+        #[arg(long)]
+        scene_threshold: Option<f64>,
+
+        /// Registered model to caption with, the default one when left out.
+        #[arg(long)]
+        model: Option<String>,
+
+        /// Without `--model`, use the default quantized model. With it, the model must be quantized.
+        #[arg(long)]
+        quantized: bool,
+    },
+    /// Print the vision encoder embedding of a local image, or write it to `--output`.
+    Embed {
+        path: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = Pooling::Cls)]
+        pooling: Pooling,
+
+        /// Scale the embedding to unit L2 norm.
+        #[arg(long)]
+        normalize: bool,
+
+        #[arg(long, value_enum, default_value_t = EmbeddingFormat::Json)]
+        format: EmbeddingFormat,
+
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Registered model to caption with, the default one when left out.
+        #[arg(long)]
+        model: Option<String>,
+
+        /// Without `--model`, use the default quantized model. With it, the model must be quantized.
+        #[arg(long)]
+        quantized: bool,
+    },
+}
+
+impl Args {
+    fn image_limits(&self) -> ImageLimits {
+        ImageLimits {
+            max_width: self.max_image_width,
+            max_height: self.max_image_height,
+            max_pixels: self.max_image_pixels,
+            max_decoded_bytes: self.max_decoded_bytes,
+        }
+    }
+}
+
+/// The `embed` command: embeds one image file and writes the result to `output` or stdout.
+fn embed_file(path: &Path, output: Option<&Path>, options: &EmbedOptions, limits: &ImageLimits) -> anyhow::Result<()> {
+    let image = std::fs::read(path)?;
+    let embedding = embed_image(&image, options, limits, &EmbeddingCache::new(0))?;
+    let bytes = embedding.encode(options.format)?;
+    match output {
+        Some(output) => std::fs::write(output, bytes)?,
+        None => io::stdout().write_all(&bytes)?,
+    }
+    Ok(())
+}
+
+/// Shared state handed to every handler.
+struct AppState {
+    args: Args,
+    hub: Arc<ChannelHub>,
+    caches: Caches,
+    index: SearchIndex,
+    /// Numbers the `/caption` requests so their channel events have an id.
+    http_requests: AtomicU64,
+}
+
+impl AppState {
+    /// Adds a finished caption to the search index when the request asked for it. Indexing is best
+    /// effort: the caption was already produced, and on the WebSocket delivered, so a failure here
+    /// is only logged.
+    fn index_caption(&self, result: &CaptionResult, image: Option<&[u8]>, options: &CaptionOptions) {
+        if !options.index {
+            return;
+        }
+        if let Err(e) = self.index.add_caption(result, image, options, &self.args.image_limits(), &self.caches.embeddings) {
+            println!("could not index image {}: {e}", result.image_id);
+        }
+    }
+}
 
 #[tokio::main]
 pub async fn main(){
+    let args = Args::parse();
+
+    let registry = match &args.models {
+        Some(path) => RegistryConfig::load(path).expect("could not read the model registry"),
+        None => RegistryConfig::builtin(),
+    };
+    models::install(registry);
+
+    match &args.command {
+        Some(Command::Video { path, format, interval_secs, scene_threshold, model, quantized }) => {
+            let mut options = VideoOptions { format: *format, interval_secs: *interval_secs, scene_threshold: *scene_threshold, ..Default::default() };
+            options.caption.model = model.clone();
+            options.caption.quantized = *quantized;
+            match caption_video(&args.ffmpeg, path, &options, &args.image_limits()) {
+                Ok(cues) => print!("{}", render_cues(&cues, options.format)),
+                Err(e) => {
+                    eprintln!("video captioning failed: {e}");
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Embed { path, pooling, normalize, format, output, model, quantized }) => {
+            let options = EmbedOptions {
+                model: model.clone(),
+                pooling: *pooling,
+                normalize: *normalize,
+                format: *format,
+                quantized: *quantized,
+                ..Default::default()
+            };
+            if let Err(e) = embed_file(path, output.as_deref(), &options, &args.image_limits()) {
+                eprintln!("embedding failed: {e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
     // initialize tracing
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    let hub = Arc::new(ChannelHub::new(args.channel_capacity));
+    let cache = CaptionCache::new(args.cache_entries, args.cache_dir.as_deref(), args.cache_disk_bytes)
+        .expect("could not open the caption cache directory");
+    let caches = Caches { captions: cache, embeddings: EmbeddingCache::new(args.embedding_cache_bytes) };
+    let index = SearchIndex::open(args.index_path.as_deref()).expect("could not open the search index");
+    let state = Arc::new(AppState { args, hub, caches, index, http_requests: AtomicU64::new(0) });
+
     // build our application with a route
-    let app = Router::new()
+    let mut routes = Router::new()
         // `GET /` goes to `root`
         .route("/", get(show_form))
-        // `POST /users` goes to `create_user`
         .route("/caption", post(create_caption))
-        .route("/ws", get(ws_handler))
+        .route("/video", post(create_video_captions))
+        .route("/embed", post(create_embedding))
+        .route("/match", post(create_match))
+        .route("/vqa", post(create_answer))
+        .route("/index/search", get(search_index))
+        .route("/index/similar", post(search_similar_upload))
+        .route("/index/similar/:image_id", get(search_similar))
+        .route("/models", get(list_models))
+        .route("/ws", get(ws_handler));
+    if state.args.admin_token.is_some() {
+        routes = routes.route("/admin/cache", get(cache_stats).delete(clear_cache));
+    } else {
+        println!("no --admin-token given, the /admin endpoints are disabled");
+    }
+    let app = routes
+        .with_state(state)
         // logging so we can see what's going on
         .layer(
             TraceLayer::new_for_http()
@@ -78,33 +372,263 @@ async fn show_form() -> Html<&'static str> {
     )
 }
 
-async fn create_caption(mut multipart: Multipart) -> Result<Response<String>, StatusCode> {
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
-        let file_name = field.file_name().unwrap().to_string();
-        let content_type = field.content_type().unwrap().to_string();
-        let data = field.bytes().await.unwrap();
+/// What an upload form carried: the first file, the options and any extra text fields.
+struct Upload<T> {
+    file: Option<Bytes>,
+    options: T,
+    /// Values of the text fields the handler asked for, by name in the order they were sent.
+    texts: Vec<(String, String)>,
+}
+
+/// Reads a multipart upload. The optional `options` field is a JSON object deserialized into `T`,
+/// fields named in `text_fields` are read as text and the first of any other field is the file;
+/// further files are ignored.
+async fn read_upload<T: DeserializeOwned + Default>(mut multipart: Multipart, text_fields: &[&str]) -> Result<Upload<T>, ApiError> {
+    let bad_request = |e: MultipartError| ApiError::new(StatusCode::BAD_REQUEST, e);
+    let mut upload = Upload { file: None, options: T::default(), texts: Vec::new() };
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "options" {
+            let text = field.text().await.map_err(bad_request)?;
+            upload.options = serde_json::from_str(&text)
+                .map_err(|e| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("invalid options: {e}")))?;
+            continue;
+        }
+        if text_fields.contains(&name.as_str()) {
+            let text = field.text().await.map_err(bad_request)?;
+            upload.texts.push((name, text));
+            continue;
+        }
+        if upload.file.is_some() {
+            continue;
+        }
+        let file_name = field.file_name().unwrap_or_default().to_string();
+        let content_type = field.content_type().unwrap_or_default().to_string();
+        let data = field.bytes().await.map_err(bad_request)?;
 
         println!(
             "Length of `{name}` (`{file_name}`: `{content_type}`) is {} bytes",
             data.len()
         );
+        upload.file = Some(data);
+    }
+    Ok(upload)
+}
+
+/// The uploaded file, or a 400 saying that no `what` was sent.
+fn required_file(file: Option<Bytes>, what: &str) -> Result<Bytes, ApiError> {
+    file.ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, format!("no {what} in the upload")))
+}
+
+async fn create_caption(State(state): State<Arc<AppState>>, multipart: Multipart) -> Result<Response<String>, ApiError> {
+    // the options are a JSON object with the same fields as a WebSocket `caption` request
+    let Upload { file: image, options, .. } = read_upload::<CaptionOptions>(multipart, &[]).await?;
+    if image.is_none() && options.image_id.is_none() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "no image in the upload and no image_id"));
+    }
 
-        let result = run_blip(data, false).unwrap();
-        return Ok(Response::builder()
+    let id = format!("http-{}", state.http_requests.fetch_add(1, Ordering::Relaxed));
+    let result = tokio::task::spawn_blocking(move || {
+        let publisher = state.hub.publisher("http", options.channel.as_deref());
+        let result = run_blip(&id, image.clone(), &options, &state.args.image_limits(), &state.caches, &publisher);
+        match &result {
+            Ok(result) => state.index_caption(result, image.as_deref(), &options),
+            Err(e) => publisher.publish(&ServerEvent::error(Some(&id), e)),
+        }
+        result
+    })
+    .await
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    match result {
+        Ok(result) => Ok(Response::builder()
             .status(StatusCode::CREATED)
-            // .body(String::from("Hello world"))
-            .body(result)
-            .unwrap());
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&result).unwrap())
+            .unwrap()),
+        Err(e) => {
+            println!("caption failed: {e}");
+            Err(e.into())
+        }
     }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    /// For "more like this", which model's entry of the image to start from when it was indexed
+    /// with several.
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    10
+}
+
+/// Keyword search over the indexed captions.
+async fn search_index(State(state): State<Arc<AppState>>, Query(query): Query<SearchQuery>) -> Json<Vec<SearchHit>> {
+    Json(state.index.search(&query.q, query.limit))
+}
+
+/// "More like this": the indexed images closest to an already indexed one.
+async fn search_similar(
+    State(state): State<Arc<AppState>>,
+    AxumPath(image_id): AxumPath<String>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchHit>>, ApiError> {
+    let Some(entry) = state.index.get(&image_id, query.model.as_deref()) else {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, format!("image {image_id} is not indexed")));
+    };
+    Ok(Json(state.index.similar(&entry.embedding, &entry.model, Some(&image_id), query.limit)))
+}
+
+/// The indexed images closest to an uploaded one, which is not added to the index.
+async fn search_similar_upload(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SearchQuery>,
+    multipart: Multipart,
+) -> Result<Json<Vec<SearchHit>>, ApiError> {
+    let Upload { file, mut options, .. } = read_upload::<EmbedOptions>(multipart, &[]).await?;
+    let data = required_file(file, "image")?;
+    // the index holds normalized CLS embeddings, compare like with like
+    options.pooling = Pooling::Cls;
+    options.normalize = true;
+
+    let hits = tokio::task::spawn_blocking(move || {
+        let embedding = embed_image(&data, &options, &state.args.image_limits(), &state.caches.embeddings)?;
+        anyhow::Ok(state.index.similar(&embedding.embedding, &embedding.model, Some(&embedding.image_id), query.limit))
+    })
+    .await
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))??;
+    Ok(Json(hits))
+}
 
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+/// The registered models, whether they are loaded yet and how much memory their weights take.
+async fn list_models() -> Json<ModelListing> {
+    Json(models::registry().list())
+}
+
+/// Rejects admin requests without the configured bearer token. The routes are only mounted when
+/// a token is configured, so a missing one is treated like a wrong one.
+fn check_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (bearer, &state.args.admin_token) {
+        (Some(bearer), Some(token)) if tokens_match(bearer, token) => Ok(()),
+        _ => Err(ApiError::new(StatusCode::UNAUTHORIZED, "missing or wrong admin token")),
+    }
+}
+
+/// Compares two tokens in time independent of where they differ. Comparing their digests also
+/// hides the length of the configured token.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (Sha256::digest(given), Sha256::digest(expected));
+    given.iter().zip(expected.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn cache_stats(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<CachesStats>, ApiError> {
+    check_admin(&state, &headers)?;
+    Ok(Json(state.caches.stats()))
+}
+
+/// Empties the caption cache (both tiers) and the embedding cache, and answers with the (now
+/// empty) stats.
+async fn clear_cache(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<CachesStats>, ApiError> {
+    check_admin(&state, &headers)?;
+    state.caches.clear()?;
+    println!("caches cleared");
+    Ok(Json(state.caches.stats()))
+}
+
+/// Returns the vision encoder embedding of an uploaded image in the requested format.
+async fn create_embedding(State(state): State<Arc<AppState>>, multipart: Multipart) -> Result<Response, ApiError> {
+    let Upload { file, options, .. } = read_upload::<EmbedOptions>(multipart, &[]).await?;
+    let data = required_file(file, "image")?;
+
+    let format = options.format;
+    let body = tokio::task::spawn_blocking(move || {
+        let embedding = embed_image(&data, &options, &state.args.image_limits(), &state.caches.embeddings)?;
+        embedding.encode(options.format)
+    })
+    .await
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))??;
+    Ok(([(header::CONTENT_TYPE, format.content_type())], body).into_response())
+}
+
+/// Scores candidate texts against an uploaded image with BLIP's image-text matching heads. Texts
+/// come from the `texts` array of the `options` field and from any number of `text` fields.
+async fn create_match(State(state): State<Arc<AppState>>, multipart: Multipart) -> Result<Json<MatchResult>, ApiError> {
+    let Upload { file, mut options, texts } = read_upload::<MatchOptions>(multipart, &["text"]).await?;
+    let data = required_file(file, "image")?;
+    options.texts.extend(texts.into_iter().map(|(_, text)| text));
+    if options.texts.is_empty() || options.texts.len() > MAX_MATCH_TEXTS {
+        let message = format!("send between 1 and {MAX_MATCH_TEXTS} texts, got {}", options.texts.len());
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message));
+    }
+
+    let result = tokio::task::spawn_blocking(move || match_texts(&data, &options, &state.args.image_limits()))
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))??;
+    Ok(Json(result))
+}
+
+/// Answers a question about an uploaded image with BLIP's VQA model. The question comes from the
+/// `question` field of `options` or from a `question` field.
+async fn create_answer(State(state): State<Arc<AppState>>, multipart: Multipart) -> Result<Json<VqaResult>, ApiError> {
+    let Upload { file, mut options, texts } = read_upload::<VqaOptions>(multipart, &["question"]).await?;
+    let data = required_file(file, "image")?;
+    if let Some((_, question)) = texts.into_iter().last() {
+        options.question = question;
+    }
+    if options.question.trim().is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "no question in the upload"));
+    }
+
+    let result = tokio::task::spawn_blocking(move || answer_question(&data, &options, &state.args.image_limits(), |_| Ok(())))
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))??;
+    Ok(Json(result))
+}
+
+/// Captions an uploaded video and answers with subtitles in the requested format.
+async fn create_video_captions(State(state): State<Arc<AppState>>, multipart: Multipart) -> Result<Response<String>, ApiError> {
+    let Upload { file, options, .. } = read_upload::<VideoOptions>(multipart, &[]).await?;
+    let data = required_file(file, "video")?;
+    println!("captioning a video of {} bytes", data.len());
+
+    let format = options.format;
+    let cues = tokio::task::spawn_blocking(move || {
+        // ffmpeg needs a seekable file for most containers
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), &data)?;
+        caption_video(&state.args.ffmpeg, file.path(), &options, &state.args.image_limits())
+    })
+    .await
+    .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?
+    .map_err(|e| {
+        println!("video captioning failed: {e}");
+        ApiError::from(e)
+    })?;
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .body(render_cues(&cues, format))
+        .unwrap())
 }
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
@@ -112,133 +636,380 @@ async fn ws_handler(
         String::from("Unknown browser")
     };
     println!("`{user_agent}` at {addr} connected.");
-    // finalize the upgrade process by returning upgrade callback.
-    // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, addr))
+    let admin = check_admin(&state, &headers).is_ok();
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, admin, state))
 }
 
-/// Actual websocket state machine (one will be spawned per connection)
-async fn handle_socket(mut socket: WebSocket, who: SocketAddr) {
-    // send a ping (unsupported by some browsers) just to kick things off and get a response
-    if socket.send(Message::Ping(vec![1, 2, 3])).await.is_ok() {
-        println!("Pinged {who}...");
-    } else {
-        println!("Could not send ping {who}!");
-        // no Error here since the only thing we can do is to close the connection.
-        // If we can not send messages, there is no way to salvage the statemachine anyway.
+/// Runs one WebSocket connection: greets the client, then splits the socket into a send task
+/// draining the connection's outbox and a receive task running the protocol.
+async fn handle_socket(mut socket: WebSocket, who: SocketAddr, admin: bool, state: Arc<AppState>) {
+    // greet the client before reading anything, so every frame it sends is handled by the
+    // protocol loop below and nothing is consumed during the handshake.
+    let args = &state.args;
+    let hello = ServerEvent::hello(args.max_in_flight, args.max_upload_bytes, args.heartbeat_secs, args.idle_timeout_secs);
+    if socket.send(hello.to_message()).await.is_err() {
+        println!("Could not send hello to {who}!");
         return;
     }
 
-    // receive single message from a client (we can either receive or send with socket).
-    // this will likely be the Pong for our Ping or a hello message from client.
-    // waiting for message from a client will block this task, but will not block other client's
-    // connections.
-    if let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            // if process_message(msg, who).await.is_break() {
-            //     return;
-            // }
-        } else {
-            println!("client {who} abruptly disconnected");
-            return;
-        }
-    }
-
-    // Since each client gets individual statemachine, we can pause handling
-    // when necessary to wait for some external event (in this case illustrated by sleeping).
-    // Waiting for this client to finish getting its greetings does not prevent other clients from
-    // connecting to server and receiving their greetings.
-    for i in 1..5 {
-        // if socket
-        //     .send(Message::Text(format!("Hi {i} times!")))
-        //     .await
-        //     .is_err()
-        // {
-        //     println!("client {who} abruptly disconnected");
-        //     return;
-        // }
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-    }
+    // By splitting socket we can send and receive at the same time. Every caption running for this
+    // connection pushes its events into one outbox, and a single send task drains it in order so
+    // token streams of concurrent requests interleave without tearing frames.
+    let (mut sender, receiver) = socket.split();
+    let (outbox, mut outbox_rx) = mpsc::channel::<Message>(64);
 
-    // By splitting socket we can send and receive at the same time. In this example we will send
-    // unsolicited messages to client based on some sort of server's internal event (i.e .timer).
-    let (mut sender, mut receiver) = socket.split();
-
-    // Spawn a task that will push several messages to the client (does not matter what client does)
-    // let mut send_task = tokio::spawn(async move {
-    //     let n_msg = 20;
-    //     for i in 0..n_msg {
-    //         // In case of any websocket error, we exit.
-    //         if sender
-    //             .send(Message::Text(format!("Server message {i} ...")))
-    //             .await
-    //             .is_err()
-    //         {
-    //             return i;
-    //         }
-    //
-    //         tokio::time::sleep(std::time::Duration::from_millis(300)).await;
-    //     }
-    //
-    //     println!("Sending close to {who}...");
-    //     if let Err(e) = sender
-    //         .send(Message::Close(Some(CloseFrame {
-    //             code: axum::extract::ws::close_code::NORMAL,
-    //             reason: Cow::from("Goodbye"),
-    //         })))
-    //         .await
-    //     {
-    //         println!("Could not send Close due to {e}, probably it is ok?");
-    //     }
-    //     n_msg
-    // });
-
-    // This second task will receive messages from client and print them on server console
-    let mut recv_task = tokio::spawn(async move {
+    let mut send_task = tokio::spawn(async move {
         let mut cnt = 0;
-        while let Some(Ok(msg)) = receiver.next().await {
+        while let Some(msg) = outbox_rx.recv().await {
+            let is_close = matches!(msg, Message::Close(_));
+            // In case of any websocket error, we exit.
+            if sender.send(msg).await.is_err() {
+                break;
+            }
             cnt += 1;
-            // print message and break if instructed to do so
-            if process_message(msg, who, &mut sender).await.is_break() {
+            if is_close {
                 break;
             }
         }
         cnt
     });
 
+    // This second task will receive messages from client, start captions for them and keep the
+    // connection alive with heartbeats until the client leaves or goes idle.
+    let mut recv_task = tokio::spawn(async move {
+        let connection = Connection::new(who, admin, outbox, state.clone());
+        receive_loop(receiver, connection, &state.args).await
+    });
+
     // If any one of the tasks exit, abort the other.
-    // tokio::select! {
-    //     rv_a = (&mut send_task) => {
-    //         match rv_a {
-    //             Ok(a) => println!("{a} messages sent to {who}"),
-    //             Err(a) => println!("Error sending messages {a:?}")
-    //         }
-    //         recv_task.abort();
-    //     },
-    //     rv_b = (&mut recv_task) => {
-    //         match rv_b {
-    //             Ok(b) => println!("Received {b} messages"),
-    //             Err(b) => println!("Error receiving messages {b:?}")
-    //         }
-    //         send_task.abort();
-    //     }
-    // }
+    tokio::select! {
+        rv_a = (&mut send_task) => {
+            match rv_a {
+                Ok(a) => println!("{a} messages sent to {who}"),
+                Err(a) => println!("Error sending messages {a:?}")
+            }
+            recv_task.abort();
+        },
+        rv_b = (&mut recv_task) => {
+            match rv_b {
+                Ok(b) => println!("Received {b} messages"),
+                Err(b) => println!("Error receiving messages {b:?}")
+            }
+            // give the send task a moment to flush a queued close frame before tearing it down
+            if tokio::time::timeout(Duration::from_secs(1), &mut send_task).await.is_err() {
+                send_task.abort();
+            }
+        }
+    }
 
     // returning from the handler closes the websocket connection
     println!("Websocket context {who} destroyed");
 }
 
-/// helper to print contents of messages to stdout. Has special treatment for Close.
-async fn process_message(msg: Message, who: SocketAddr, sender: &mut SplitSink<WebSocket, Message>) -> ControlFlow<(), ()> {
+/// Reads frames until the client disconnects, sending heartbeat pings on the configured interval
+/// and closing the socket once it has been idle (no requests, nothing in flight and nothing
+/// subscribed to) for too long.
+async fn receive_loop(mut receiver: SplitStream<WebSocket>, mut connection: Connection, args: &Args) -> usize {
+    let mut cnt = 0;
+    let who = connection.who;
+    let mut heartbeat = (args.heartbeat_secs > 0).then(|| {
+        let period = Duration::from_secs(args.heartbeat_secs);
+        tokio::time::interval_at(Instant::now() + period, period)
+    });
+    let mut idle = IdleTimer::new((args.idle_timeout_secs > 0).then(|| Duration::from_secs(args.idle_timeout_secs)));
+
+    loop {
+        tokio::select! {
+            msg = receiver.next() => {
+                let Some(Ok(msg)) = msg else {
+                    println!("client {who} abruptly disconnected");
+                    break;
+                };
+                cnt += 1;
+                if matches!(msg, Message::Text(_) | Message::Binary(_)) {
+                    idle.touch();
+                }
+                if process_message(msg, &mut connection).await.is_break() {
+                    break;
+                }
+            }
+            _ = tick(&mut heartbeat) => {
+                if connection.outbox.send(Message::Ping(b"heartbeat".to_vec())).await.is_err() {
+                    break;
+                }
+            }
+            _ = idle.expired() => {
+                if !idle.should_close(connection.has_in_flight() || connection.is_listening()) {
+                    continue;
+                }
+                println!("closing idle connection {who}");
+                let close = Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: Cow::from("idle timeout"),
+                }));
+                let _ = connection.outbox.send(close).await;
+                break;
+            }
+        }
+    }
+    cnt
+}
+
+/// Waits for the next heartbeat, or forever when heartbeats are disabled.
+async fn tick(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// A `caption` or `vqa` request that is waiting for its image to arrive as the next binary frame.
+enum PendingRequest {
+    Caption { id: String, options: CaptionOptions },
+    Vqa { id: String, options: VqaOptions },
+}
+
+impl PendingRequest {
+    fn id(&self) -> &str {
+        match self {
+            Self::Caption { id, .. } | Self::Vqa { id, .. } => id,
+        }
+    }
+}
+
+/// Per-connection state owned by the receive loop. Captions run on blocking threads and report
+/// back through `outbox`, which the send task drains in order.
+struct Connection {
+    who: SocketAddr,
+    /// Whether the upgrade request carried the admin token.
+    admin: bool,
+    outbox: mpsc::Sender<Message>,
+    pending: Option<PendingRequest>,
+    upload: Option<ChunkedUpload>,
+    live: Option<LiveSession>,
+    subscriptions: HashMap<String, Subscription>,
+    in_flight: InFlight,
+    state: Arc<AppState>,
+}
+
+impl Connection {
+    fn new(who: SocketAddr, admin: bool, outbox: mpsc::Sender<Message>, state: Arc<AppState>) -> Self {
+        Self {
+            who,
+            admin,
+            outbox,
+            pending: None,
+            upload: None,
+            live: None,
+            subscriptions: HashMap::new(),
+            in_flight: InFlight::new(state.args.max_in_flight),
+            state,
+        }
+    }
+
+    fn has_in_flight(&self) -> bool {
+        !self.in_flight.is_empty()
+    }
+
+    /// Whether the client is waiting for events it did not ask for one by one, from a live
+    /// session or channel subscriptions.
+    fn is_listening(&self) -> bool {
+        self.live.is_some() || !self.subscriptions.is_empty()
+    }
+
+    /// Queues an event for the client, breaking if the send task has gone away.
+    async fn send(&self, event: ServerEvent) -> ControlFlow<(), ()> {
+        match self.outbox.send(event.to_message()).await {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        }
+    }
+
+    /// Starts a caption in the background so the receive loop can accept the next request right
+    /// away. Rejects duplicate ids and requests over the in-flight limit with an `error` event.
+    async fn start_caption(&self, id: String, image: Option<Vec<u8>>, options: CaptionOptions) -> ControlFlow<(), ()> {
+        let admission = match self.in_flight.admit(&id) {
+            Ok(admission) => admission,
+            Err(message) => return self.send(ServerEvent::error(Some(&id), message)).await,
+        };
+
+        let who = self.who;
+        let outbox = self.outbox.clone();
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || {
+            let publisher = state.hub.publisher("ws", options.channel.as_deref());
+            let limits = state.args.image_limits();
+            match run_blip_ws(&id, image.as_deref(), &options, &limits, &state.caches, &outbox, &publisher) {
+                Ok(result) => state.index_caption(&result, image.as_deref(), &options),
+                Err(e) => {
+                    println!(">>> caption {id} for {who} failed: {e}");
+                    let error = ServerEvent::error(Some(&id), e);
+                    publisher.publish(&error);
+                    let _ = outbox.blocking_send(error.to_message());
+                }
+            }
+            drop(admission);
+        });
+        ControlFlow::Continue(())
+    }
+
+    /// Answers a question in the background like [`Self::start_caption`], streaming `token`
+    /// events and finishing with an `answer` event.
+    async fn start_vqa(&self, id: String, image: Vec<u8>, options: VqaOptions) -> ControlFlow<(), ()> {
+        if options.question.trim().is_empty() {
+            return self.send(ServerEvent::error(Some(&id), "the question is empty")).await;
+        }
+        let admission = match self.in_flight.admit(&id) {
+            Ok(admission) => admission,
+            Err(message) => return self.send(ServerEvent::error(Some(&id), message)).await,
+        };
+
+        let who = self.who;
+        let outbox = self.outbox.clone();
+        let limits = self.state.args.image_limits();
+        tokio::task::spawn_blocking(move || {
+            let send = |event: ServerEvent| {
+                outbox
+                    .blocking_send(event.to_message())
+                    .map_err(|_| anyhow::Error::msg("websocket closed"))
+            };
+            let result = answer_question(&image, &options, &limits, |text| send(ServerEvent::Token { id: id.clone(), text }))
+                .and_then(|result| send(ServerEvent::Answer { id: id.clone(), result }));
+            if let Err(e) = result {
+                println!(">>> question {id} for {who} failed: {e}");
+                let _ = outbox.blocking_send(ServerEvent::error(Some(&id), e).to_message());
+            }
+            drop(admission);
+        });
+        ControlFlow::Continue(())
+    }
+}
+
+/// Dispatches one frame of the JSON protocol (see `ws_protocol`). Has special treatment for Close.
+async fn process_message(msg: Message, connection: &mut Connection) -> ControlFlow<(), ()> {
+    let who = connection.who;
     match msg {
         Message::Text(t) => {
             println!(">>> {who} sent str: {t:?}");
-            sender.send(Message::Text(String::from("Hello World"))).await.unwrap()
+            if let Some(pending) = connection.pending.take() {
+                connection.send(ServerEvent::error(Some(pending.id()), "expected a binary image frame")).await?;
+            }
+            match parse_client_message(&t) {
+                Ok(ClientMessage::Caption { id, options, image: Some(image) }) => {
+                    match decode_inline_image(&image, connection.state.args.max_upload_bytes) {
+                        Ok(image) => return connection.start_caption(id, Some(image), options).await,
+                        Err(e) => {
+                            let error = ServerEvent::error(Some(&id), format!("invalid base64 image: {e}"));
+                            connection.send(error).await?;
+                        }
+                    }
+                }
+                Ok(ClientMessage::Caption { id, options, image: None }) if options.image_id.is_some() => {
+                    return connection.start_caption(id, None, options).await;
+                }
+                Ok(ClientMessage::Caption { id, options, image: None }) => {
+                    connection.pending = Some(PendingRequest::Caption { id, options });
+                }
+                Ok(ClientMessage::Vqa { id, options, image: Some(image) }) => {
+                    match decode_inline_image(&image, connection.state.args.max_upload_bytes) {
+                        Ok(image) => return connection.start_vqa(id, image, options).await,
+                        Err(e) => {
+                            let error = ServerEvent::error(Some(&id), format!("invalid base64 image: {e}"));
+                            connection.send(error).await?;
+                        }
+                    }
+                }
+                Ok(ClientMessage::Vqa { id, options, image: None }) => {
+                    connection.pending = Some(PendingRequest::Vqa { id, options });
+                }
+                Ok(ClientMessage::UploadBegin { id, size, content_type, options }) => {
+                    if let Some(upload) = &connection.upload {
+                        let message = format!("upload {} is still in progress", upload.id);
+                        connection.send(ServerEvent::error(Some(&id), message)).await?;
+                    } else {
+                        match ChunkedUpload::begin(id.clone(), size, &content_type, options, connection.state.args.max_upload_bytes) {
+                            Ok(upload) => connection.upload = Some(upload),
+                            Err(e) => connection.send(ServerEvent::error(Some(&id), e)).await?,
+                        }
+                    }
+                }
+                Ok(ClientMessage::UploadEnd { id, sha256 }) => {
+                    match connection.upload.take() {
+                        Some(upload) if upload.id == id => match upload.finish(&sha256) {
+                            Ok((image, options)) => return connection.start_caption(id, Some(image), options).await,
+                            Err(e) => connection.send(ServerEvent::error(Some(&id), e)).await?,
+                        },
+                        other => {
+                            connection.upload = other;
+                            connection.send(ServerEvent::error(Some(&id), "no upload with this id")).await?;
+                        }
+                    }
+                }
+                Ok(ClientMessage::LiveStart { id, settings }) => {
+                    let hub = connection.state.hub.clone();
+                    let limits = connection.state.args.image_limits();
+                    let outbox = connection.outbox.clone();
+                    if let Err(e) = LiveSession::start(&mut connection.live, id.clone(), settings, outbox, hub, limits).await {
+                        connection.send(ServerEvent::error(Some(&id), e)).await?;
+                    }
+                }
+                Ok(ClientMessage::LiveStop { id }) => {
+                    match connection.live.take() {
+                        Some(live) if live.id == id => {}
+                        other => {
+                            connection.live = other;
+                            connection.send(ServerEvent::error(Some(&id), "no live session with this id")).await?;
+                        }
+                    }
+                }
+                Ok(ClientMessage::Subscribe { channel }) => {
+                    if connection.subscriptions.contains_key(&channel) {
+                        return ControlFlow::Continue(());
+                    }
+                    match connection.state.hub.subscribe(&channel, connection.admin, connection.subscriptions.len()) {
+                        Ok(receiver) => {
+                            let subscription = Subscription::start(channel.clone(), receiver, connection.outbox.clone());
+                            connection.subscriptions.insert(channel, subscription);
+                        }
+                        Err(e) => connection.send(ServerEvent::error(None, e)).await?,
+                    }
+                }
+                Ok(ClientMessage::Unsubscribe { channel }) => {
+                    connection.subscriptions.remove(&channel);
+                }
+                Err(e) => {
+                    connection.send(ServerEvent::error(None, format!("invalid message: {e}"))).await?;
+                }
+            }
         }
         Message::Binary(d) => {
             println!(">>> {} sent {} bytes", who, d.len());
 
-            run_blip_ws(d, false, sender).await.unwrap();
+            match connection.pending.take() {
+                Some(PendingRequest::Caption { id, options }) => {
+                    return connection.start_caption(id, Some(d), options).await;
+                }
+                Some(PendingRequest::Vqa { id, options }) => {
+                    return connection.start_vqa(id, d, options).await;
+                }
+                None if connection.upload.is_some() => {
+                    let upload = connection.upload.as_mut().unwrap();
+                    if let Err(e) = upload.push_chunk(&d) {
+                        // a bad chunk poisons the whole upload, the client has to start over
+                        let id = connection.upload.take().unwrap().id;
+                        connection.send(ServerEvent::error(Some(&id), e)).await?;
+                    }
+                }
+                None if connection.live.is_some() => {
+                    connection.live.as_mut().unwrap().push_frame(d);
+                }
+                None => {
+                    let error = ServerEvent::error(None, "binary frame without a preceding caption request");
+                    connection.send(error).await?;
+                }
+            }
         }
         Message::Close(c) => {
             if let Some(cf) = c {
@@ -0,0 +1,466 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use candle_core::DType;
+use serde::{Deserialize, Serialize};
+use crate::backend::{load_backend, CaptionBackend, BACKENDS, DEFAULT_BACKEND};
+use crate::load_image::PreprocessError;
+
+/// Where a model's files come from: a Hugging Face repo, or a local directory laid out like one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Source {
+    Hub {
+        repo: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revision: Option<String>,
+    },
+    Local { dir: PathBuf },
+}
+
+impl Source {
+    /// Path of `file`, downloading it first for Hub sources.
+    pub fn get(&self, file: &str) -> anyhow::Result<PathBuf> {
+        match self {
+            Self::Hub { repo, revision } => {
+                let api = hf_hub::api::sync::Api::new()?;
+                let api = match revision {
+                    Some(revision) => api.repo(hf_hub::Repo::with_revision(repo.clone(), hf_hub::RepoType::Model, revision.clone())),
+                    None => api.model(repo.clone()),
+                };
+                Ok(api.get(file)?)
+            }
+            Self::Local { dir } => {
+                let path = dir.join(file);
+                if !path.exists() {
+                    anyhow::bail!("{} does not exist", path.display());
+                }
+                Ok(path)
+            }
+        }
+    }
+}
+
+/// Precision the full precision weights are loaded in. Quantized weights keep their own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WeightDtype {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl From<WeightDtype> for DType {
+    fn from(dtype: WeightDtype) -> Self {
+        match dtype {
+            WeightDtype::F32 => DType::F32,
+            WeightDtype::F16 => DType::F16,
+            WeightDtype::Bf16 => DType::BF16,
+        }
+    }
+}
+
+fn default_backend() -> String {
+    DEFAULT_BACKEND.to_string()
+}
+
+/// One entry of the model registry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelConfig {
+    /// What requests select the model by.
+    pub name: String,
+    /// Architecture of the checkpoint, one of `backend::BACKENDS`.
+    #[serde(default = "default_backend")]
+    pub backend: String,
+    pub source: Source,
+    /// Weights file in `source`, `model.safetensors` when left out. Quantized models must name
+    /// their GGUF file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weights: Option<String>,
+    /// Where the tokenizer and config files come from when `source` only holds weights, as for
+    /// a GGUF conversion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_source: Option<Source>,
+    #[serde(default)]
+    pub quantized: bool,
+    #[serde(default)]
+    pub dtype: WeightDtype,
+}
+
+impl ModelConfig {
+    /// Path of the weights file.
+    pub fn weights_file(&self) -> anyhow::Result<PathBuf> {
+        match (&self.weights, self.quantized) {
+            (Some(file), _) => self.source.get(file),
+            (None, false) => self.source.get("model.safetensors"),
+            (None, true) => anyhow::bail!("model {} is quantized but names no weights file", self.name),
+        }
+    }
+
+    /// Path of a tokenizer or config file.
+    pub fn config_file(&self, file: &str) -> anyhow::Result<PathBuf> {
+        self.config_source.as_ref().unwrap_or(&self.source).get(file)
+    }
+}
+
+fn default_matcher() -> Source {
+    Source::Hub { repo: "Salesforce/blip-itm-large-coco".to_string(), revision: None }
+}
+
+fn default_answerer() -> Source {
+    Source::Hub { repo: "Salesforce/blip-vqa-base".to_string(), revision: None }
+}
+
+/// The `--models` file: the models to serve and which ones requests get when they name none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryConfig {
+    pub default: String,
+    /// Used for requests that only set `quantized`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_quantized: Option<String>,
+    pub models: Vec<ModelConfig>,
+    /// Checkpoint with BLIP's image-text matching heads, used by `/match` and `best_of`.
+    #[serde(default = "default_matcher")]
+    pub matcher: Source,
+    /// Checkpoint of BLIP's visual question answering model, used by `/vqa`.
+    #[serde(default = "default_answerer")]
+    pub answerer: Source,
+}
+
+impl RegistryConfig {
+    /// What the server ran before there was a registry: BLIP large in full precision and as q4k.
+    pub fn builtin() -> Self {
+        let large = Source::Hub { repo: "Salesforce/blip-image-captioning-large".to_string(), revision: None };
+        Self {
+            default: "blip-large".to_string(),
+            default_quantized: Some("blip-large-q4k".to_string()),
+            models: vec![
+                ModelConfig {
+                    name: "blip-large".to_string(),
+                    backend: default_backend(),
+                    source: Source::Hub {
+                        repo: "Salesforce/blip-image-captioning-large".to_string(),
+                        revision: Some("refs/pr/18".to_string()),
+                    },
+                    weights: None,
+                    config_source: Some(large.clone()),
+                    quantized: false,
+                    dtype: WeightDtype::F32,
+                },
+                ModelConfig {
+                    name: "blip-large-q4k".to_string(),
+                    backend: default_backend(),
+                    source: Source::Hub { repo: "lmz/candle-blip".to_string(), revision: None },
+                    weights: Some("blip-image-captioning-large-q4k.gguf".to_string()),
+                    config_source: Some(large),
+                    quantized: true,
+                    dtype: WeightDtype::F32,
+                },
+            ],
+            matcher: default_matcher(),
+            answerer: default_answerer(),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let config: Self = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| anyhow::anyhow!("invalid model registry {}: {e}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for model in &self.models {
+            if !names.insert(model.name.as_str()) {
+                anyhow::bail!("model {} is registered twice", model.name);
+            }
+            if !BACKENDS.contains(&model.backend.as_str()) {
+                anyhow::bail!("model {} has unknown backend {:?}, expected one of {BACKENDS:?}", model.name, model.backend);
+            }
+        }
+        for default in std::iter::once(&self.default).chain(&self.default_quantized) {
+            if !names.contains(default.as_str()) {
+                anyhow::bail!("default model {default} is not registered");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A model whose weights are in memory. Requests clone `backend`, which shares the weights.
+pub struct LoadedModel {
+    pub backend: Box<dyn CaptionBackend>,
+    pub memory_bytes: u64,
+}
+
+/// What `GET /models` reports about a registered model.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelInfo {
+    #[serde(flatten)]
+    pub config: ModelConfig,
+    pub loaded: bool,
+    /// Size of the weights in memory, once loaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+}
+
+/// A model loaded by the first request that needs it and kept for all later ones.
+#[derive(Default)]
+pub struct LoadOnce<T> {
+    loaded: OnceLock<T>,
+    /// Held while loading, so the model is loaded once even when several requests want it first.
+    /// Requests for other models go ahead meanwhile.
+    loading: Mutex<()>,
+}
+
+impl<T> LoadOnce<T> {
+    pub const fn new() -> Self {
+        Self { loaded: OnceLock::new(), loading: Mutex::new(()) }
+    }
+
+    /// The model if some request loaded it already.
+    pub fn get(&self) -> Option<&T> {
+        self.loaded.get()
+    }
+
+    /// The model, running `load` if no request did yet.
+    pub fn get_or_load(&self, load: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<&T> {
+        if let Some(model) = self.loaded.get() {
+            return Ok(model);
+        }
+        let _loading = self.loading.lock().unwrap();
+        if let Some(model) = self.loaded.get() {
+            return Ok(model);
+        }
+        let _ = self.loaded.set(load()?);
+        Ok(self.loaded.get().expect("the model was just loaded"))
+    }
+}
+
+struct Entry {
+    config: ModelConfig,
+    loaded: LoadOnce<Arc<LoadedModel>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelListing {
+    pub default: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_quantized: Option<String>,
+    pub models: Vec<ModelInfo>,
+    pub matcher: Source,
+    pub answerer: Source,
+}
+
+/// The named models this server can caption with, loaded on first use and kept side by side,
+/// and where the matching and question answering models come from.
+pub struct ModelRegistry {
+    entries: Vec<Entry>,
+    default: String,
+    default_quantized: Option<String>,
+    matcher: Source,
+    answerer: Source,
+}
+
+static REGISTRY: OnceLock<ModelRegistry> = OnceLock::new();
+
+/// Makes `config` the registry every request resolves its model in. Only the first call counts.
+pub fn install(config: RegistryConfig) {
+    let _ = REGISTRY.set(ModelRegistry::new(config));
+}
+
+/// The installed registry, or the built-in one when nothing was installed.
+pub fn registry() -> &'static ModelRegistry {
+    REGISTRY.get_or_init(|| ModelRegistry::new(RegistryConfig::builtin()))
+}
+
+impl ModelRegistry {
+    fn new(config: RegistryConfig) -> Self {
+        let entries = config
+            .models
+            .into_iter()
+            .map(|config| Entry { config, loaded: LoadOnce::new() })
+            .collect();
+        Self {
+            entries,
+            default: config.default,
+            default_quantized: config.default_quantized,
+            matcher: config.matcher,
+            answerer: config.answerer,
+        }
+    }
+
+    /// Where the image-text matching model is loaded from.
+    pub fn matcher(&self) -> &Source {
+        &self.matcher
+    }
+
+    /// Where the visual question answering model is loaded from.
+    pub fn answerer(&self) -> &Source {
+        &self.answerer
+    }
+
+    /// Name of the model a request runs on, see [`Self::resolve_config`].
+    pub fn resolve(&self, model: Option<&str>, quantized: bool) -> anyhow::Result<&str> {
+        Ok(&self.resolve_config(model, quantized)?.name)
+    }
+
+    /// The model a request runs on: the one it names, otherwise the default or, for requests that
+    /// only set `quantized`, the default quantized model. Naming a full precision model while
+    /// asking for `quantized` is rejected rather than silently running the full model.
+    pub fn resolve_config(&self, model: Option<&str>, quantized: bool) -> anyhow::Result<&ModelConfig> {
+        let name = match (model, quantized) {
+            (Some(name), _) => name,
+            (None, false) => &self.default,
+            (None, true) => self
+                .default_quantized
+                .as_deref()
+                .ok_or_else(|| PreprocessError::InvalidRequest("no default quantized model is configured".to_string()))?,
+        };
+        let config = self
+            .configs()
+            .find(|config| config.name == name)
+            .ok_or_else(|| PreprocessError::InvalidRequest(format!("unknown model {name:?}, see /models")))?;
+        if quantized && !config.quantized {
+            return Err(PreprocessError::InvalidRequest(format!(
+                "model {name:?} is not quantized, leave out `quantized` or pick a quantized model"
+            ))
+            .into());
+        }
+        Ok(config)
+    }
+
+    /// The model called `name`, loading it if no request did yet.
+    pub fn load(&self, name: &str) -> anyhow::Result<Arc<LoadedModel>> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.config.name == name)
+            .ok_or_else(|| PreprocessError::InvalidRequest(format!("unknown model {name:?}, see /models")))?;
+        let model = entry.loaded.get_or_load(|| {
+            eprintln!("loading model {name}");
+            let (backend, memory_bytes) = load_backend(&entry.config)?;
+            eprintln!("loaded model {name}, {memory_bytes} bytes of weights");
+            Ok(Arc::new(LoadedModel { backend, memory_bytes }))
+        })?;
+        Ok(model.clone())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.configs().map(|config| config.name.as_str())
+    }
+
+    pub fn configs(&self) -> impl Iterator<Item = &ModelConfig> {
+        self.entries.iter().map(|entry| &entry.config)
+    }
+
+    pub fn list(&self) -> ModelListing {
+        let models = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let model = entry.loaded.get();
+                ModelInfo {
+                    config: entry.config.clone(),
+                    loaded: model.is_some(),
+                    memory_bytes: model.map(|model| model.memory_bytes),
+                }
+            })
+            .collect();
+        ModelListing {
+            default: self.default.clone(),
+            default_quantized: self.default_quantized.clone(),
+            models,
+            matcher: self.matcher.clone(),
+            answerer: self.answerer.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_defaults_and_named_models() {
+        let registry = ModelRegistry::new(RegistryConfig::builtin());
+        assert_eq!(registry.resolve(None, false).unwrap(), "blip-large");
+        assert_eq!(registry.resolve(None, true).unwrap(), "blip-large-q4k");
+        assert_eq!(registry.resolve(Some("blip-large-q4k"), false).unwrap(), "blip-large-q4k");
+        assert_eq!(registry.resolve(Some("blip-large-q4k"), true).unwrap(), "blip-large-q4k");
+        let unknown = registry.resolve(Some("blip-huge"), false).unwrap_err();
+        assert!(matches!(unknown.downcast_ref(), Some(PreprocessError::InvalidRequest(_))));
+        assert_eq!(unknown.to_string(), "invalid request: unknown model \"blip-huge\", see /models");
+    }
+
+    #[test]
+    fn quantized_requests_never_run_a_full_precision_model() {
+        let registry = ModelRegistry::new(RegistryConfig::builtin());
+        assert!(registry.resolve(Some("blip-large"), true).is_err());
+        let mut config = RegistryConfig::builtin();
+        config.default_quantized = None;
+        assert!(ModelRegistry::new(config).resolve(None, true).is_err());
+    }
+
+    #[test]
+    fn registry_files_are_validated() {
+        let mut duplicate = RegistryConfig::builtin();
+        duplicate.models[1].name = duplicate.models[0].name.clone();
+        assert!(duplicate.validate().is_err());
+
+        let mut unknown_backend = RegistryConfig::builtin();
+        unknown_backend.models[0].backend = "llava".to_string();
+        assert!(unknown_backend.validate().is_err());
+
+        let mut missing_default = RegistryConfig::builtin();
+        missing_default.default = "blip-base".to_string();
+        assert!(missing_default.validate().is_err());
+
+        RegistryConfig::builtin().validate().unwrap();
+    }
+
+    #[test]
+    fn parses_hub_and_local_sources() {
+        let config: RegistryConfig = serde_json::from_value(serde_json::json!({
+            "default": "local",
+            "models": [
+                { "name": "local", "source": { "dir": "/models/blip" }, "dtype": "f16" },
+                { "name": "hub", "source": { "repo": "Salesforce/blip-image-captioning-base" } },
+            ],
+        }))
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.models[0].source, Source::Local { dir: PathBuf::from("/models/blip") });
+        assert_eq!(config.models[0].dtype, WeightDtype::F16);
+        assert_eq!(config.models[1].backend, DEFAULT_BACKEND);
+        assert_eq!(config.matcher, default_matcher());
+        assert_eq!(config.answerer, default_answerer());
+    }
+
+    #[test]
+    fn loading_dispatches_on_the_backend() {
+        let mut config = RegistryConfig::builtin();
+        config.models[0].backend = "llava".to_string();
+        let registry = ModelRegistry::new(config);
+        let error = registry.load("blip-large").err().unwrap();
+        assert_eq!(error.to_string(), "model blip-large has unknown backend \"llava\", expected one of [\"blip\"]");
+    }
+
+    #[test]
+    fn matcher_and_answerer_can_be_replaced() {
+        let config: RegistryConfig = serde_json::from_value(serde_json::json!({
+            "default": "hub",
+            "models": [{ "name": "hub", "source": { "repo": "Salesforce/blip-image-captioning-base" } }],
+            "matcher": { "dir": "/models/itm" },
+            "answerer": { "repo": "Salesforce/blip-vqa-capfilt-large", "revision": "main" },
+        }))
+        .unwrap();
+        let registry = ModelRegistry::new(config);
+        assert_eq!(registry.matcher(), &Source::Local { dir: PathBuf::from("/models/itm") });
+        assert_eq!(
+            registry.answerer(),
+            &Source::Hub { repo: "Salesforce/blip-vqa-capfilt-large".to_string(), revision: Some("main".to_string()) }
+        );
+    }
+}
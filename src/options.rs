@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use crate::animation::FrameCaption;
+use crate::blip_itm::MatchScore;
+use crate::load_image::{ImageInfo, PreprocessOptions};
+use crate::regions::{Region, RegionCaption, TileCaption, TileGrid};
+
+/// Per-request knobs for a caption. Everything has a default so clients only send what they want
+/// to change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptionOptions {
+    /// Name of the registered model to caption with, see `/models`. Without one the default model
+    /// is used.
+    pub model: Option<String>,
+    /// Without a `model`, use the default quantized model instead of the default one. A `model`
+    /// named alongside it must be quantized.
+    pub quantized: bool,
+    pub seed: u64,
+    /// Sampling temperature, `None` means greedy decoding.
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    /// Longest caption to generate, in tokens. Defaults to, and may not exceed, what the model's
+    /// decoder has positions for.
+    pub max_tokens: Option<usize>,
+    /// Also publish this caption to the named channel, on top of the `all` firehose.
+    pub channel: Option<String>,
+    /// Caption an earlier upload by the `image_id` it was answered with instead of sending the
+    /// image again. Only works while its embeddings are still cached.
+    pub image_id: Option<String>,
+    /// Add the image, its caption and its embedding to the search index.
+    pub index: bool,
+    /// Boxes to caption on their own, in addition to the whole image.
+    pub regions: Vec<Region>,
+    /// Also caption every tile of this grid, for images too detailed for a single 384x384 pass.
+    pub tiles: Option<TileGrid>,
+    /// For animated GIF and WebP uploads, caption this many frames spread across the animation.
+    pub frames: Option<usize>,
+    /// Sample this many captions and return the one the image-text matching head ranks highest.
+    /// Without a `temperature` the candidates are sampled at 1.0.
+    pub best_of: Option<usize>,
+    #[serde(flatten)]
+    pub preprocess: PreprocessOptions,
+}
+
+impl Default for CaptionOptions {
+    fn default() -> Self {
+        Self {
+            model: None,
+            quantized: false,
+            seed: 1337,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            channel: None,
+            image_id: None,
+            index: false,
+            regions: Vec::new(),
+            tiles: None,
+            frames: None,
+            best_of: None,
+            preprocess: PreprocessOptions::default(),
+        }
+    }
+}
+
+/// Everything a finished caption request produces. This is the `/caption` response body and the
+/// payload of the WebSocket `done` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptionResult {
+    pub caption: String,
+    /// Reference to this image for follow-up requests, the SHA-256 of the upload.
+    #[serde(default)]
+    pub image_id: String,
+    /// Set when the caption was served from the cache instead of being generated.
+    #[serde(default)]
+    pub cached: bool,
+    /// How the upload was preprocessed, e.g. the EXIF orientation that was applied.
+    pub image: ImageInfo,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<RegionCaption>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tiles: Vec<TileCaption>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<FrameCaption>,
+    /// The frame captions with repeats removed, in order of first appearance.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub distinct_captions: Vec<String>,
+    /// For `best_of`, every distinct candidate with its scores, best first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<MatchScore>,
+}
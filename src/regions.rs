@@ -0,0 +1,251 @@
+use candle_core::Tensor;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use crate::captioner::Captioner;
+use crate::load_image::PreprocessError;
+use crate::options::CaptionOptions;
+
+/// Most regions a single request may ask captions for.
+pub const MAX_REGIONS: usize = 64;
+
+/// Most rows or columns a tile grid may have.
+pub const MAX_TILES_PER_AXIS: u32 = 8;
+
+/// Largest overlap between neighbouring tiles, as a fraction of the tile size.
+pub const MAX_TILE_OVERLAP: f32 = 0.5;
+
+/// A bounding box in pixels of the upright image, i.e. after the EXIF orientation was applied.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionCaption {
+    #[serde(flatten)]
+    pub region: Region,
+    pub caption: String,
+}
+
+/// Splits a large image into a `rows` x `cols` grid of tiles that overlap their neighbours by
+/// `overlap` (a fraction of the tile size), so details lost at 384x384 get captions of their own.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TileGrid {
+    pub rows: u32,
+    pub cols: u32,
+    #[serde(default)]
+    pub overlap: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileCaption {
+    pub row: u32,
+    pub col: u32,
+    #[serde(flatten)]
+    pub caption: RegionCaption,
+}
+
+/// Start and length of `count` tiles covering `length` pixels with the given overlap. The last
+/// tile absorbs rounding so the grid always reaches the far edge.
+fn tile_spans(length: u32, count: u32, overlap: f32) -> Vec<(u32, u32)> {
+    let tile = length as f32 / (count as f32 - (count - 1) as f32 * overlap);
+    let step = tile * (1.0 - overlap);
+    (0..count)
+        .map(|i| {
+            let start = (i as f32 * step).round() as u32;
+            let end = if i + 1 == count {
+                length
+            } else {
+                ((i as f32 * step + tile).round() as u32).min(length)
+            };
+            (start, end - start)
+        })
+        .collect()
+}
+
+impl TileGrid {
+    /// Lays the grid over a `width` x `height` image, returning each tile's row, column and box in
+    /// row-major order.
+    pub fn regions(&self, width: u32, height: u32) -> Result<Vec<(u32, u32, Region)>, PreprocessError> {
+        let axes_ok = (1..=MAX_TILES_PER_AXIS).contains(&self.rows) && (1..=MAX_TILES_PER_AXIS).contains(&self.cols);
+        if !axes_ok {
+            return Err(PreprocessError::InvalidRequest(format!(
+                "tile grid must have 1 to {MAX_TILES_PER_AXIS} rows and columns, got {}x{}",
+                self.rows, self.cols
+            )));
+        }
+        if !(0.0..=MAX_TILE_OVERLAP).contains(&self.overlap) {
+            return Err(PreprocessError::InvalidRequest(format!(
+                "tile overlap must be between 0 and {MAX_TILE_OVERLAP}, got {}",
+                self.overlap
+            )));
+        }
+        if width < self.cols || height < self.rows {
+            return Err(PreprocessError::InvalidRequest(format!(
+                "a {width}x{height} image is too small for a {}x{} tile grid",
+                self.rows, self.cols
+            )));
+        }
+        let xs = tile_spans(width, self.cols, self.overlap);
+        let ys = tile_spans(height, self.rows, self.overlap);
+        let mut tiles = Vec::with_capacity(xs.len() * ys.len());
+        for (row, &(y, tile_height)) in ys.iter().enumerate() {
+            for (col, &(x, tile_width)) in xs.iter().enumerate() {
+                let region = Region { x, y, width: tile_width, height: tile_height };
+                tiles.push((row as u32, col as u32, region));
+            }
+        }
+        Ok(tiles)
+    }
+}
+
+/// Cuts every region out of the decoded image, rejecting boxes that are empty or fall outside it.
+pub fn crop_regions(img: &DynamicImage, regions: &[Region]) -> Result<Vec<DynamicImage>, PreprocessError> {
+    if regions.len() > MAX_REGIONS {
+        return Err(PreprocessError::InvalidRequest(format!(
+            "{} regions requested, at most {MAX_REGIONS} are supported",
+            regions.len()
+        )));
+    }
+    regions
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            let fits = r.x.checked_add(r.width).is_some_and(|right| right <= img.width())
+                && r.y.checked_add(r.height).is_some_and(|bottom| bottom <= img.height());
+            if r.width == 0 || r.height == 0 || !fits {
+                return Err(PreprocessError::InvalidRequest(format!(
+                    "region {i} ({}x{} at {},{}) does not fit the {}x{} image",
+                    r.width, r.height, r.x, r.y, img.width(), img.height()
+                )));
+            }
+            Ok(img.crop_imm(r.x, r.y, r.width, r.height))
+        })
+        .collect()
+}
+
+/// Generates a caption per region from `embeds`, whose rows `offset..` belong to `regions` in
+/// order. The embeddings are computed up front in batches, so only decoding runs per region.
+pub fn caption_regions(
+    captioner: &mut Captioner,
+    embeds: &Tensor,
+    offset: usize,
+    regions: &[Region],
+    options: &CaptionOptions,
+) -> anyhow::Result<Vec<RegionCaption>> {
+    regions
+        .iter()
+        .enumerate()
+        .map(|(i, region)| {
+            let region_embeds = embeds.narrow(0, offset + i, 1)?;
+            let caption = captioner.generate(&region_embeds, options, |_| Ok(()))?;
+            Ok(RegionCaption { region: *region, caption })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_without_overlap_split_evenly() {
+        assert_eq!(tile_spans(300, 3, 0.0), vec![(0, 100), (100, 100), (200, 100)]);
+    }
+
+    #[test]
+    fn spans_overlap_by_the_requested_fraction() {
+        // 3 tiles with 25% overlap: tile = 300 / (3 - 2 * 0.25) = 120, step = 90
+        assert_eq!(tile_spans(300, 3, 0.25), vec![(0, 120), (90, 120), (180, 120)]);
+    }
+
+    #[test]
+    fn last_span_reaches_the_far_edge() {
+        for (length, count, overlap) in [(101, 3, 0.0), (1000, 7, 0.3), (9, 8, 0.5)] {
+            let spans = tile_spans(length, count, overlap);
+            assert_eq!(spans.len(), count as usize);
+            assert_eq!(spans[0].0, 0);
+            let (start, len) = spans[count as usize - 1];
+            assert_eq!(start + len, length);
+            assert!(spans.iter().all(|&(_, len)| len > 0));
+        }
+    }
+
+    #[test]
+    fn regions_are_row_major() {
+        let grid = TileGrid { rows: 2, cols: 3, overlap: 0.0 };
+        let tiles = grid.regions(300, 200).unwrap();
+        let cells: Vec<_> = tiles.iter().map(|&(row, col, region)| (row, col, region.x, region.y)).collect();
+        assert_eq!(cells, vec![(0, 0, 0, 0), (0, 1, 100, 0), (0, 2, 200, 0), (1, 0, 0, 100), (1, 1, 100, 100), (1, 2, 200, 100)]);
+    }
+
+    #[test]
+    fn regions_reject_invalid_grids() {
+        let grid = |rows, cols, overlap| TileGrid { rows, cols, overlap };
+        assert!(grid(0, 2, 0.0).regions(100, 100).is_err());
+        assert!(grid(2, MAX_TILES_PER_AXIS + 1, 0.0).regions(100, 100).is_err());
+        assert!(grid(2, 2, 0.6).regions(100, 100).is_err());
+        assert!(grid(2, 2, 0.0).regions(1, 100).is_err());
+    }
+
+    fn image(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 0])))
+    }
+
+    fn region(x: u32, y: u32, width: u32, height: u32) -> Region {
+        Region { x, y, width, height }
+    }
+
+    fn rejected(img: &DynamicImage, regions: &[Region]) -> String {
+        match crop_regions(img, regions) {
+            Err(e @ PreprocessError::InvalidRequest(_)) => e.to_string(),
+            other => panic!("expected an invalid request, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn crops_follow_their_boxes() {
+        let img = image(10, 8);
+        let crops = crop_regions(&img, &[region(0, 0, 10, 8), region(2, 3, 4, 5)]).unwrap();
+        assert_eq!((crops[0].width(), crops[0].height()), (10, 8));
+        assert_eq!((crops[1].width(), crops[1].height()), (4, 5));
+        assert_eq!(crops[1].to_rgb8().get_pixel(0, 0).0, [2, 3, 0]);
+    }
+
+    #[test]
+    fn boxes_outside_the_image_are_rejected() {
+        let img = image(10, 8);
+        let message = rejected(&img, &[region(0, 0, 10, 8), region(6, 0, 5, 8)]);
+        assert_eq!(message, "invalid request: region 1 (5x8 at 6,0) does not fit the 10x8 image");
+        rejected(&img, &[region(0, 8, 1, 1)]);
+        rejected(&img, &[region(0, 0, 10, 9)]);
+    }
+
+    #[test]
+    fn empty_boxes_are_rejected() {
+        let img = image(10, 8);
+        rejected(&img, &[region(2, 2, 0, 4)]);
+        rejected(&img, &[region(2, 2, 4, 0)]);
+    }
+
+    #[test]
+    fn overflowing_coordinates_are_rejected() {
+        let img = image(10, 8);
+        rejected(&img, &[region(u32::MAX, 0, 2, 2)]);
+        rejected(&img, &[region(1, 0, u32::MAX, 2)]);
+        rejected(&img, &[region(0, u32::MAX - 1, 2, 2)]);
+    }
+
+    #[test]
+    fn at_most_max_regions_are_cropped() {
+        let img = image(10, 8);
+        let regions = vec![region(0, 0, 1, 1); MAX_REGIONS];
+        assert_eq!(crop_regions(&img, &regions).unwrap().len(), MAX_REGIONS);
+        let regions = vec![region(0, 0, 1, 1); MAX_REGIONS + 1];
+        let message = rejected(&img, &regions);
+        assert_eq!(message, format!("invalid request: {} regions requested, at most {MAX_REGIONS} are supported", MAX_REGIONS + 1));
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+use candle_core::Tensor;
+use image::DynamicImage;
+use crate::blip_itm::{ImageTextMatcher, MatchScore};
+use crate::captioner::Captioner;
+use crate::load_image::{image_to_tensor, PreprocessError};
+use crate::options::CaptionOptions;
+
+/// Most candidates a best-of request may sample.
+pub const MAX_BEST_OF: usize = 16;
+
+/// Sampling temperature for the candidates when the request asked for greedy decoding, which
+/// would produce the same caption `n` times.
+const DEFAULT_TEMPERATURE: f64 = 1.0;
+
+/// Rejects a `best_of` outside 1 to [`MAX_BEST_OF`].
+pub fn check_best_of(n: usize) -> Result<(), PreprocessError> {
+    if !(1..=MAX_BEST_OF).contains(&n) {
+        return Err(PreprocessError::InvalidRequest(format!("best_of must be between 1 and {MAX_BEST_OF}, got {n}")));
+    }
+    Ok(())
+}
+
+/// Samples `n` captions from one set of image embeddings, each with its own seed, and ranks the
+/// distinct ones by how well BLIP's image-text matching head thinks they fit the decoded `image`.
+/// The best candidate comes first.
+pub fn best_of(
+    captioner: &mut Captioner,
+    image_embeds: &Tensor,
+    image: &DynamicImage,
+    options: &CaptionOptions,
+    n: usize,
+) -> anyhow::Result<Vec<MatchScore>> {
+    check_best_of(n)?;
+    let mut sampling = options.clone();
+    sampling.temperature = options.temperature.or(Some(DEFAULT_TEMPERATURE));
+    let mut samples = Vec::with_capacity(n);
+    for i in 0..n {
+        sampling.seed = options.seed.wrapping_add(i as u64);
+        samples.push(captioner.generate(image_embeds, &sampling, |_| Ok(()))?);
+    }
+    let candidates = distinct(samples);
+    println!("ranking {} distinct candidates out of {n}", candidates.len());
+
+    let matcher = ImageTextMatcher::shared()?;
+    // the matcher has its own preprocessing, which need not be the captioning backend's
+    let image = image_to_tensor(image, &options.preprocess, matcher.image_spec())?;
+    let scores = matcher.score(&matcher.encode_image(&image)?, &candidates)?;
+    Ok(rank(scores))
+}
+
+/// Keeps the first of every repeated caption, in sampling order, so each is scored once.
+fn distinct(captions: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    captions.into_iter().filter(|caption| seen.insert(caption.clone())).collect()
+}
+
+/// Orders scored candidates best first, by match probability and then similarity. Candidates
+/// that tie on both keep their sampling order.
+fn rank(mut scores: Vec<MatchScore>) -> Vec<MatchScore> {
+    scores.sort_by(|a, b| {
+        b.match_probability
+            .total_cmp(&a.match_probability)
+            .then(b.similarity.total_cmp(&a.similarity))
+    });
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(text: &str, match_probability: f32, similarity: f32) -> MatchScore {
+        MatchScore { text: text.to_string(), match_probability, similarity }
+    }
+
+    fn texts(scores: &[MatchScore]) -> Vec<&str> {
+        scores.iter().map(|score| score.text.as_str()).collect()
+    }
+
+    #[test]
+    fn repeated_captions_are_scored_once() {
+        let samples = ["a cat", "a dog", "a cat", "a cat on a sofa", "a dog"].map(String::from).to_vec();
+        assert_eq!(distinct(samples), vec!["a cat", "a dog", "a cat on a sofa"]);
+        assert!(distinct(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn candidates_rank_by_match_probability_first() {
+        let ranked = rank(vec![score("a", 0.2, 0.9), score("b", 0.8, 0.1), score("c", 0.5, 0.5)]);
+        assert_eq!(texts(&ranked), vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn ties_fall_back_to_similarity_then_sampling_order() {
+        let ranked = rank(vec![
+            score("a", 0.5, 0.1),
+            score("b", 0.5, 0.3),
+            score("c", 0.5, 0.3),
+            score("d", 0.9, 0.0),
+        ]);
+        assert_eq!(texts(&ranked), vec!["d", "b", "c", "a"]);
+    }
+}
@@ -1,101 +1,200 @@
 use axum::body::Bytes;
-use candle_core::{Device, DType, Tensor};
-use candle_nn::VarBuilder;
-use candle_transformers::models::{blip, quantized_blip};
-use tokenizers::Tokenizer;
-use crate::load_image::load_image;
-use crate::token_output_stream::TokenOutputStream;
-use anyhow::Error as E;
+use candle_core::Tensor;
+use serde::Serialize;
+use crate::animation::{distinct_captions, sample_frames, FrameCaption};
+use crate::caption_cache::{image_hash, CacheStats, CaptionCache};
+use crate::captioner::Captioner;
+use crate::channels::Publisher;
+use crate::embedding_cache::{embedding_key, CachedEmbedding, EmbeddingCache, EmbeddingStats};
+use crate::load_image::{decode_image, image_to_tensor, ImageLimits, PreprocessError};
+use crate::options::{CaptionOptions, CaptionResult};
+use crate::regions::{caption_regions, crop_regions, TileCaption};
+use crate::rerank;
+use crate::ws_protocol::{ServerEvent, Stage};
 
-enum Model {
-    M(blip::BlipForConditionalGeneration),
-    Q(quantized_blip::BlipForConditionalGeneration),
+/// The caches a caption request reads and fills.
+pub struct Caches {
+    pub captions: CaptionCache,
+    pub embeddings: EmbeddingCache,
 }
 
-impl Model {
-    fn text_decoder_forward(&mut self, xs: &Tensor, img_xs: &Tensor) -> candle_core::Result<Tensor> {
-        match self {
-            Self::M(m) => m.text_decoder().forward(xs, img_xs),
-            Self::Q(m) => m.text_decoder().forward(xs, img_xs),
-        }
+#[derive(Debug, Clone, Serialize)]
+pub struct CachesStats {
+    pub captions: CacheStats,
+    pub embeddings: EmbeddingStats,
+}
+
+impl Caches {
+    pub fn stats(&self) -> CachesStats {
+        CachesStats { captions: self.captions.stats(), embeddings: self.embeddings.stats() }
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        self.embeddings.clear();
+        self.captions.clear()
     }
 }
 
-const SEP_TOKEN_ID: u32 = 102;
+/// The image a request captions: a fresh upload, or only the `image_id` of an earlier one whose
+/// embeddings are hopefully still cached.
+pub struct ImageInput<'a> {
+    pub id: String,
+    pub data: Option<&'a [u8]>,
+}
 
-pub fn run_blip(image: Bytes, quantized: bool) -> anyhow::Result<String> {
-    let model_file = {
-        let api = hf_hub::api::sync::Api::new()?;
-        if quantized {
-            let api = api.model("lmz/candle-blip".to_string());
-            api.get("blip-image-captioning-large-q4k.gguf")?
-        } else {
-            let api = api.repo(hf_hub::Repo::with_revision(
-                "Salesforce/blip-image-captioning-large".to_string(),
-                hf_hub::RepoType::Model,
-                "refs/pr/18".to_string(),
-            ));
-            api.get("model.safetensors")?
-        }
-    };
-    let tokenizer = {
-        let api = hf_hub::api::sync::Api::new()?;
-        let api = api.model("Salesforce/blip-image-captioning-large".to_string());
-        api.get("tokenizer.json")?
-    };
-    let tokenizer = Tokenizer::from_file(tokenizer).map_err(E::msg)?;
-    let mut tokenizer = TokenOutputStream::new(tokenizer);
-    let mut logits_processor =
-        candle_transformers::generation::LogitsProcessor::new(1337, None, None);
+impl<'a> ImageInput<'a> {
+    /// Uploads are identified by their hash; without one the request's `image_id` is used.
+    pub fn new(data: Option<&'a [u8]>, options: &CaptionOptions) -> anyhow::Result<Self> {
+        let id = match (data, &options.image_id) {
+            (Some(data), _) => image_hash(data),
+            (None, Some(id)) => id.clone(),
+            (None, None) => anyhow::bail!("the request has neither an image nor an image_id"),
+        };
+        Ok(Self { id, data })
+    }
 
-    let config = blip::Config::image_captioning_large();
+    fn not_cached(&self, reason: &'static str) -> anyhow::Error {
+        PreprocessError::NotCached { image_id: self.id.clone(), reason }.into()
+    }
+}
 
-    let device = Device::Cpu;
-    let (image_embeds, device, mut model) = if quantized {
-        let device = Device::Cpu;
-        let image = load_image(image)?.to_device(&device)?;
-        println!("loaded image {image:?}");
+/// Preprocesses the image and any requested regions, tiles and animation frames, runs them through
+/// the vision encoder in batches and generates the captions. Only the whole-image caption is
+/// streamed to `on_token`; `on_stage` hears about each step as it starts. With `best_of` the
+/// winning caption is only known after ranking, so it reaches `on_token` in one piece.
+///
+/// The whole-image embeddings come from `embeddings` when they are cached and are stored there
+/// otherwise. Regions, tiles, frames and best_of always need the uploaded pixels.
+pub fn caption_image(
+    captioner: &mut Captioner,
+    input: &ImageInput,
+    options: &CaptionOptions,
+    limits: &ImageLimits,
+    embeddings: &EmbeddingCache,
+    mut on_stage: impl FnMut(Stage) -> anyhow::Result<()>,
+    mut on_token: impl FnMut(String) -> anyhow::Result<()>,
+) -> anyhow::Result<CaptionResult> {
+    captioner.check_options(options)?;
+    on_stage(Stage::Preprocessing)?;
+    let key = embedding_key(&input.id, captioner.model(), &options.preprocess);
+    let cached = embeddings.get(&key);
+    let best_of = options.best_of.filter(|&n| n != 1);
+    let needs_pixels = !options.regions.is_empty() || options.tiles.is_some() || options.frames.is_some() || best_of.is_some();
+    let decoded = match input.data {
+        Some(data) if cached.is_none() || needs_pixels => Some((data, decode_image(data, &options.preprocess, limits)?)),
+        Some(_) => None,
+        None if cached.is_none() => return Err(input.not_cached("its embeddings are not cached")),
+        None if needs_pixels => return Err(input.not_cached("regions, tiles, frames and best_of need the pixels")),
+        None => None,
+    };
 
-        let vb = quantized_blip::VarBuilder::from_gguf(model_file, &device)?;
-        let model = quantized_blip::BlipForConditionalGeneration::new(&config, vb)?;
-        let image_embeds = image.unsqueeze(0)?.apply(model.vision_model())?;
-        (image_embeds, device, Model::Q(model))
-    } else {
-        let image = load_image(image)?.to_device(&device)?;
-        println!("loaded image {image:?}");
+    let mut inputs = Vec::new();
+    let mut tiles = Vec::new();
+    let mut tile_regions = Vec::new();
+    let mut frames = Vec::new();
+    let info = match &decoded {
+        Some((data, (img, info))) => {
+            tiles = match &options.tiles {
+                Some(grid) => grid.regions(img.width(), img.height())?,
+                None => Vec::new(),
+            };
+            frames = match options.frames {
+                Some(count) => sample_frames(data, count, options.preprocess.background, limits)?.unwrap_or_default(),
+                None => Vec::new(),
+            };
+            let spec = captioner.image_spec();
+            if cached.is_none() {
+                inputs.push(image_to_tensor(img, &options.preprocess, spec)?);
+            }
+            tile_regions = tiles.iter().map(|&(_, _, region)| region).collect();
+            for crop in crop_regions(img, &options.regions)?.iter().chain(&crop_regions(img, &tile_regions)?) {
+                inputs.push(image_to_tensor(crop, &options.preprocess, spec)?);
+            }
+            for frame in &frames {
+                inputs.push(image_to_tensor(&frame.image, &options.preprocess, spec)?);
+            }
+            info.clone()
+        }
+        None => cached.as_ref().expect("checked above").info.clone(),
+    };
+    println!(
+        "loaded image {info:?} with {} regions, {} tiles and {} frames{}",
+        options.regions.len(),
+        tiles.len(),
+        frames.len(),
+        if cached.is_some() { ", embeddings cached" } else { "" }
+    );
 
-        let vb =
-            unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], DType::F32, &device)? };
-        let model = blip::BlipForConditionalGeneration::new(&config, vb)?;
-        let image_embeds = image.unsqueeze(0)?.apply(model.vision_model())?;
-        (image_embeds, device, Model::M(model))
+    on_stage(Stage::Encoding)?;
+    let embeds = match cached {
+        Some(cached) if inputs.is_empty() => cached.embeds,
+        Some(cached) => Tensor::cat(&[&cached.embeds, &captioner.encode_batch(&inputs)?], 0)?,
+        None => {
+            let embeds = captioner.encode_batch(&inputs)?;
+            // copy, so the cache does not keep the whole batch alive through a view
+            let whole = embeds.narrow(0, 0, 1)?.copy()?;
+            embeddings.insert(&key, CachedEmbedding { embeds: whole, info: info.clone() });
+            embeds
+        }
     };
 
-    let mut token_ids = vec![30522u32];
-    let mut result = String::from("");
-    for index in 0..1000 {
-        let context_size = if index > 0 { 1 } else { token_ids.len() };
-        let start_pos = token_ids.len().saturating_sub(context_size);
-        let input_ids = Tensor::new(&token_ids[start_pos..], &device)?.unsqueeze(0)?;
-        let logits = model.text_decoder_forward(&input_ids, &image_embeds)?;
-        let logits = logits.squeeze(0)?;
-        let logits = logits.get(logits.dim(0)? - 1)?;
-        let token = logits_processor.sample(&logits)?;
-        if token == SEP_TOKEN_ID {
-            break;
+    on_stage(Stage::Generating)?;
+    let image_embeds = embeds.narrow(0, 0, 1)?;
+    let (caption, candidates) = match (best_of, &decoded) {
+        (Some(n), Some((_, (img, _)))) => {
+            let candidates = rerank::best_of(captioner, &image_embeds, img, options, n)?;
+            let caption = candidates[0].text.clone();
+            on_token(caption.clone())?;
+            (caption, candidates)
         }
-        token_ids.push(token);
-        if let Some(t) = tokenizer.next_token(token)? {
+        _ => (captioner.generate(&image_embeds, options, on_token)?, Vec::new()),
+    };
+    let regions = caption_regions(captioner, &embeds, 1, &options.regions, options)?;
+    let tiles: Vec<_> = caption_regions(captioner, &embeds, 1 + regions.len(), &tile_regions, options)?
+        .into_iter()
+        .zip(&tiles)
+        .map(|(caption, &(row, col, _))| TileCaption { row, col, caption })
+        .collect();
+    let offset = 1 + regions.len() + tiles.len();
+    let frames = frames
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            let caption = captioner.generate(&embeds.narrow(0, offset + i, 1)?, options, |_| Ok(()))?;
+            Ok(FrameCaption { index: frame.index, timestamp_ms: frame.timestamp_ms, caption })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let distinct_captions = distinct_captions(&frames);
+    Ok(CaptionResult {
+        caption,
+        image_id: input.id.clone(),
+        cached: false,
+        image: info,
+        regions,
+        tiles,
+        frames,
+        distinct_captions,
+        candidates,
+    })
+}
+
+/// Captions `image`, or the earlier upload named by `options.image_id`, for the HTTP endpoint,
+/// echoing tokens to stdout and publishing them to the caption channels under request `id` as
+/// they are generated. Cache hits skip the model and only publish the final `done` event.
+pub fn run_blip(id: &str, image: Option<Bytes>, options: &CaptionOptions, limits: &ImageLimits, caches: &Caches, publisher: &Publisher) -> anyhow::Result<CaptionResult> {
+    let input = ImageInput::new(image.as_deref(), options)?;
+    let result = caches.captions.get_or_caption(&input.id, options, || {
+        let mut captioner = Captioner::load(options.model.as_deref(), options.quantized)?;
+        let result = caption_image(&mut captioner, &input, options, limits, &caches.embeddings, |_| Ok(()), |t| {
             use std::io::Write;
             print!("{t}");
-            result += &*t;
             std::io::stdout().flush()?;
-        }
-    }
-    if let Some(rest) = tokenizer.decode_rest().map_err(E::msg)? {
-        print!("{rest}");
-        result += &*rest;
-    }
-    println!();
+            publisher.publish(&ServerEvent::Token { id: id.to_string(), text: t });
+            Ok(())
+        })?;
+        println!();
+        Ok(result)
+    })?;
+    publisher.publish(&ServerEvent::Done { id: id.to_string(), result: result.clone() });
     Ok(result)
-}
\ No newline at end of file
+}
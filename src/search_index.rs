@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use crate::embed::{embed_image, pool, EmbedOptions, Pooling};
+use crate::embedding_cache::{embedding_key, EmbeddingCache};
+use crate::load_image::{ImageLimits, PreprocessError};
+use crate::models;
+use crate::options::{CaptionOptions, CaptionResult};
+
+/// Most hits a single search returns.
+pub const MAX_SEARCH_RESULTS: usize = 100;
+
+/// One indexed image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub image_id: String,
+    pub caption: String,
+    /// Registered model the embedding was made with. Embeddings of different models are not
+    /// comparable, so similarity search only looks at entries made with the same one.
+    pub model: String,
+    /// L2-normalized CLS embedding of the image.
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub image_id: String,
+    pub caption: String,
+    pub model: String,
+    /// Share of query words found in the caption for keyword search, cosine similarity for
+    /// similarity search.
+    pub score: f32,
+}
+
+/// Lowercased alphanumeric words of a caption or query.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// Entries are keyed by image and model, an image captioned with two models is indexed twice.
+type EntryKey = (String, String);
+
+fn entry_key(entry: &IndexEntry) -> EntryKey {
+    (entry.image_id.clone(), entry.model.clone())
+}
+
+struct Inner {
+    entries: HashMap<EntryKey, IndexEntry>,
+    /// Append-only log of entries, later lines replace earlier ones for the same image and model.
+    file: Option<File>,
+}
+
+/// Captions and embeddings of the images captioned with `index: true`, searchable by keyword and
+/// by similarity. With a path the index is kept in a JSON lines file and reloaded on start.
+pub struct SearchIndex {
+    inner: Mutex<Inner>,
+}
+
+impl SearchIndex {
+    /// Opens the index stored at `path`, compacting its log, or an in-memory index without one.
+    /// A crash in the middle of [`Self::insert`] leaves a torn last line, which is dropped with a
+    /// warning; an unreadable line anywhere else fails.
+    pub fn open(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut entries = HashMap::new();
+        let file = match path {
+            Some(path) => {
+                if path.exists() {
+                    let log = std::fs::read(path)?;
+                    let lines: Vec<_> = log.split(|&b| b == b'\n').filter(|line| !line.is_empty()).collect();
+                    for (i, line) in lines.iter().enumerate() {
+                        match serde_json::from_slice::<IndexEntry>(line) {
+                            Ok(entry) => {
+                                entries.insert(entry_key(&entry), entry);
+                            }
+                            Err(e) if i + 1 == lines.len() => {
+                                println!("dropping the torn last line of {}: {e}", path.display());
+                            }
+                            Err(e) => anyhow::bail!("line {} of {} is not an index entry: {e}", i + 1, path.display()),
+                        }
+                    }
+                }
+                // compact into a fresh file first, so a crash halfway leaves the old log intact
+                let tmp = path.with_extension("tmp");
+                let mut file = File::create(&tmp)?;
+                for entry in entries.values() {
+                    serde_json::to_writer(&mut file, entry)?;
+                    file.write_all(b"\n")?;
+                }
+                std::fs::rename(&tmp, path)?;
+                println!("loaded {} indexed images from {}", entries.len(), path.display());
+                Some(File::options().append(true).open(path)?)
+            }
+            None => None,
+        };
+        Ok(Self { inner: Mutex::new(Inner { entries, file }) })
+    }
+
+    pub fn insert(&self, entry: IndexEntry) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(file) = &mut inner.file {
+            serde_json::to_writer(&mut *file, &entry)?;
+            file.write_all(b"\n")?;
+        }
+        inner.entries.insert(entry_key(&entry), entry);
+        Ok(())
+    }
+
+    /// The entry for `image_id` made with `model`. Without a model the one made with the default
+    /// model is preferred, otherwise the first by model name.
+    pub fn get(&self, image_id: &str, model: Option<&str>) -> Option<IndexEntry> {
+        let inner = self.inner.lock().unwrap();
+        if let Some(model) = model {
+            return inner.entries.get(&(image_id.to_string(), model.to_string())).cloned();
+        }
+        let default = models::registry().resolve(None, false).ok();
+        inner
+            .entries
+            .values()
+            .filter(|entry| entry.image_id == image_id)
+            .min_by_key(|entry| (Some(entry.model.as_str()) != default, &entry.model))
+            .cloned()
+    }
+
+    /// Indexes a finished caption. The embedding comes from the embedding cache when it is still
+    /// there, otherwise `image` is encoded again; requests that referenced an `image_id` have no
+    /// image to fall back on.
+    pub fn add_caption(
+        &self,
+        result: &CaptionResult,
+        image: Option<&[u8]>,
+        options: &CaptionOptions,
+        limits: &ImageLimits,
+        embeddings: &EmbeddingCache,
+    ) -> anyhow::Result<()> {
+        let model = models::registry().resolve(options.model.as_deref(), options.quantized)?;
+        let embedding = match image {
+            Some(image) => {
+                let embed_options = EmbedOptions {
+                    model: Some(model.to_string()),
+                    pooling: Pooling::Cls,
+                    normalize: true,
+                    preprocess: options.preprocess.clone(),
+                    ..Default::default()
+                };
+                embed_image(image, &embed_options, limits, embeddings)?.embedding
+            }
+            None => {
+                let key = embedding_key(&result.image_id, model, &options.preprocess);
+                let cached = embeddings.get(&key).ok_or_else(|| PreprocessError::NotCached {
+                    image_id: result.image_id.clone(),
+                    reason: "indexing needs its embeddings, which are not cached",
+                })?;
+                pool(&cached.embeds, Pooling::Cls, true)?.to_vec1()?
+            }
+        };
+        self.insert(IndexEntry {
+            image_id: result.image_id.clone(),
+            caption: result.caption.clone(),
+            model: model.to_string(),
+            embedding,
+        })
+    }
+
+    /// Ranks captions by the share of query words they contain.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        let query: Vec<_> = words(query).collect();
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let inner = self.inner.lock().unwrap();
+        let hits = inner.entries.values().filter_map(|entry| {
+            let caption: Vec<_> = words(&entry.caption).collect();
+            let found = query.iter().filter(|w| caption.contains(w)).count();
+            (found > 0).then(|| SearchHit {
+                image_id: entry.image_id.clone(),
+                caption: entry.caption.clone(),
+                model: entry.model.clone(),
+                score: found as f32 / query.len() as f32,
+            })
+        });
+        top(hits, limit)
+    }
+
+    /// Ranks the indexed images made with `model` by cosine similarity to a normalized
+    /// `embedding`, leaving out `exclude` so "more like this" does not return the image itself.
+    pub fn similar(&self, embedding: &[f32], model: &str, exclude: Option<&str>, limit: usize) -> Vec<SearchHit> {
+        let inner = self.inner.lock().unwrap();
+        let hits = inner
+            .entries
+            .values()
+            .filter(|entry| entry.model == model && Some(entry.image_id.as_str()) != exclude)
+            .map(|entry| SearchHit {
+                image_id: entry.image_id.clone(),
+                caption: entry.caption.clone(),
+                model: entry.model.clone(),
+                score: dot(embedding, &entry.embedding),
+            });
+        top(hits, limit)
+    }
+}
+
+/// The `limit` best hits, best first.
+fn top(hits: impl Iterator<Item = SearchHit>, limit: usize) -> Vec<SearchHit> {
+    let mut hits: Vec<_> = hits.collect();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.image_id.cmp(&b.image_id)));
+    hits.truncate(limit.min(MAX_SEARCH_RESULTS));
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(image_id: &str, caption: &str, model: &str, embedding: [f32; 2]) -> IndexEntry {
+        IndexEntry {
+            image_id: image_id.to_string(),
+            caption: caption.to_string(),
+            model: model.to_string(),
+            embedding: embedding.to_vec(),
+        }
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.image_id.as_str()).collect()
+    }
+
+    fn index() -> SearchIndex {
+        let index = SearchIndex::open(None).unwrap();
+        index.insert(entry("a", "a cat on a sofa", "blip-large", [1.0, 0.0])).unwrap();
+        index.insert(entry("b", "a dog on the beach", "blip-large", [0.6, 0.8])).unwrap();
+        index.insert(entry("c", "A cat and a dog", "blip-large", [0.0, 1.0])).unwrap();
+        index.insert(entry("d", "a cat", "blip-large-q4k", [1.0, 0.0])).unwrap();
+        index
+    }
+
+    #[test]
+    fn search_ranks_by_share_of_query_words() {
+        let hits = index().search("Cat, dog!", 10);
+        assert_eq!(ids(&hits), vec!["c", "a", "b", "d"]);
+        assert_eq!(hits[0].score, 1.0);
+        assert_eq!(hits[1].score, 0.5);
+        assert!(index().search("giraffe", 10).is_empty());
+        assert!(index().search(" ,", 10).is_empty());
+    }
+
+    #[test]
+    fn similar_stays_within_the_model_and_skips_the_query_image() {
+        let hits = index().similar(&[1.0, 0.0], "blip-large", Some("a"), 10);
+        assert_eq!(ids(&hits), vec!["b", "c"]);
+        assert!((hits[0].score - 0.6).abs() < 1e-6);
+        assert_eq!(ids(&index().similar(&[1.0, 0.0], "blip-large-q4k", None, 10)), vec!["d"]);
+    }
+
+    #[test]
+    fn top_breaks_ties_by_id_and_caps_the_limit() {
+        let hit = |image_id: &str, score| SearchHit { image_id: image_id.to_string(), caption: String::new(), model: String::new(), score };
+        let hits = top([hit("b", 0.5), hit("a", 0.5), hit("c", 0.9)].into_iter(), 2);
+        assert_eq!(ids(&hits), vec!["c", "a"]);
+        let many = (0..MAX_SEARCH_RESULTS + 10).map(|i| hit(&i.to_string(), 1.0));
+        assert_eq!(top(many, usize::MAX).len(), MAX_SEARCH_RESULTS);
+    }
+
+    #[test]
+    fn reopening_replays_and_compacts_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.jsonl");
+        let index = SearchIndex::open(Some(&path)).unwrap();
+        index.insert(entry("a", "a cat", "blip-large", [1.0, 0.0])).unwrap();
+        index.insert(entry("b", "a dog", "blip-large", [0.0, 1.0])).unwrap();
+        index.insert(entry("a", "a black cat", "blip-large", [1.0, 0.0])).unwrap();
+        drop(index);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        let index = SearchIndex::open(Some(&path)).unwrap();
+        assert_eq!(index.get("a", None).unwrap().caption, "a black cat");
+        assert_eq!(index.get("b", None).unwrap().caption, "a dog");
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn a_torn_last_line_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.jsonl");
+        let line = serde_json::to_string(&entry("a", "a cat", "blip-large", [1.0, 0.0])).unwrap();
+        std::fs::write(&path, format!("{line}\n{}", &line[..line.len() / 2])).unwrap();
+        let index = SearchIndex::open(Some(&path)).unwrap();
+        assert_eq!(index.get("a", None).unwrap().caption, "a cat");
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn a_corrupt_line_before_the_end_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.jsonl");
+        let line = serde_json::to_string(&entry("a", "a cat", "blip-large", [1.0, 0.0])).unwrap();
+        std::fs::write(&path, format!("{{\"image_id\n{line}\n")).unwrap();
+        assert!(SearchIndex::open(Some(&path)).is_err());
+    }
+
+    #[test]
+    fn an_image_is_indexed_once_per_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.jsonl");
+        let index = SearchIndex::open(Some(&path)).unwrap();
+        index.insert(entry("a", "a cat", "blip-large-q4k", [0.0, 1.0])).unwrap();
+        index.insert(entry("a", "a tabby cat", "blip-large", [1.0, 0.0])).unwrap();
+        index.insert(entry("b", "a dog", "blip-large-q4k", [0.0, 1.0])).unwrap();
+        drop(index);
+
+        let index = SearchIndex::open(Some(&path)).unwrap();
+        assert_eq!(index.get("a", Some("blip-large")).unwrap().caption, "a tabby cat");
+        assert_eq!(index.get("a", Some("blip-large-q4k")).unwrap().caption, "a cat");
+        assert!(index.get("b", Some("blip-large")).is_none());
+        // without a model the default one wins, then whatever there is
+        assert_eq!(index.get("a", None).unwrap().model, "blip-large");
+        assert_eq!(index.get("b", None).unwrap().model, "blip-large-q4k");
+
+        let hits = index.search("cat", 10);
+        let found: Vec<_> = hits.iter().map(|hit| (hit.image_id.as_str(), hit.model.as_str())).collect();
+        assert_eq!(found.len(), 2);
+        assert!(found.contains(&("a", "blip-large")) && found.contains(&("a", "blip-large-q4k")));
+        assert_eq!(ids(&index.similar(&[0.0, 1.0], "blip-large-q4k", Some("b"), 10)), vec!["a"]);
+    }
+}
@@ -0,0 +1,125 @@
+use std::borrow::Cow;
+use std::io::Read;
+use std::sync::{Arc, OnceLock};
+use flate2::read::GzDecoder;
+use image::RgbaImage;
+use resvg::{tiny_skia, usvg};
+use crate::load_image::{ImageLimits, PreprocessError};
+
+/// How far into an upload we look for an `<svg` tag before deciding it is not an SVG.
+const SNIFF_BYTES: usize = 4096;
+
+/// Largest a compressed SVG may inflate to. Markup this long is already far past any drawing worth
+/// captioning, and stopping here keeps a gzip bomb from filling memory.
+const MAX_INFLATED_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Whether the upload looks like SVG markup or gzip-compressed SVG, which the image crate does not
+/// decode.
+pub fn is_svg(data: &[u8]) -> bool {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        return true;
+    }
+    let head = &data[..data.len().min(SNIFF_BYTES)];
+    let text = String::from_utf8_lossy(head);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    text.starts_with('<') && text.contains("<svg")
+}
+
+/// System fonts are only scanned once, the first time an SVG with text shows up.
+fn fontdb() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut db = usvg::fontdb::Database::new();
+            db.load_system_fonts();
+            Arc::new(db)
+        })
+        .clone()
+}
+
+/// Inflates gzip-compressed SVG, giving up once it grows past [`MAX_INFLATED_BYTES`]. Plain
+/// markup is passed through as it is.
+fn inflate(data: &[u8]) -> Result<Cow<'_, [u8]>, PreprocessError> {
+    if !data.starts_with(&[0x1f, 0x8b]) {
+        return Ok(Cow::Borrowed(data));
+    }
+    let mut svg = Vec::new();
+    GzDecoder::new(data)
+        .take(MAX_INFLATED_BYTES + 1)
+        .read_to_end(&mut svg)
+        .map_err(|e| PreprocessError::Invalid(e.to_string()))?;
+    if svg.len() as u64 > MAX_INFLATED_BYTES {
+        return Err(PreprocessError::Invalid(format!("compressed SVG inflates past {MAX_INFLATED_BYTES} bytes")));
+    }
+    Ok(Cow::Owned(svg))
+}
+
+/// Rasterizes an SVG so its longest side is `size` pixels, keeping the aspect ratio. The result
+/// keeps its transparency so it goes through the same background compositing as other images.
+/// Images referenced by path are never loaded, only ones embedded as data URLs.
+pub fn rasterize(data: &[u8], size: u32, limits: &ImageLimits) -> Result<RgbaImage, PreprocessError> {
+    let mut options = usvg::Options {
+        fontdb: fontdb(),
+        ..Default::default()
+    };
+    options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+    let tree = usvg::Tree::from_data(&inflate(data)?, &options).map_err(|e| PreprocessError::Invalid(e.to_string()))?;
+
+    let declared = tree.size();
+    let scale = size as f32 / declared.width().max(declared.height());
+    let width = (declared.width() * scale).round().max(1.0) as u32;
+    let height = (declared.height() * scale).round().max(1.0) as u32;
+    limits.check(width, height, width as u64 * height as u64 * 4)?;
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| PreprocessError::Invalid(format!("cannot rasterize at {width}x{height}")))?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // tiny-skia works in premultiplied alpha, the compositing step expects straight alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    Ok(RgbaImage::from_raw(width, height, pixels).expect("pixmap holds width * height pixels"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10"><rect width="20" height="10" fill="red"/></svg>"#;
+
+    fn gzip(chunks: impl IntoIterator<Item = Vec<u8>>) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for chunk in chunks {
+            encoder.write_all(&chunk).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn rasterizes_plain_and_compressed_svg() {
+        for data in [SVG.as_bytes().to_vec(), gzip([SVG.as_bytes().to_vec()])] {
+            assert!(is_svg(&data));
+            let img = rasterize(&data, 40, &ImageLimits::default()).unwrap();
+            assert_eq!(img.dimensions(), (40, 20));
+        }
+    }
+
+    #[test]
+    fn gzip_bombs_stop_at_the_limit() {
+        // a valid start followed by whitespace that inflates past the limit
+        let padding = (0..=MAX_INFLATED_BYTES / (1024 * 1024)).map(|_| vec![b' '; 1024 * 1024]);
+        let bomb = gzip(std::iter::once(b"<svg".to_vec()).chain(padding));
+        assert!(bomb.len() < 1024 * 1024);
+        let message = rasterize(&bomb, 40, &ImageLimits::default()).unwrap_err().to_string();
+        assert!(message.contains("inflates past"), "{message}");
+    }
+}
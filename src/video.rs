@@ -0,0 +1,405 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::captioner::{Captioner, MAX_ENCODE_BATCH};
+use crate::load_image::{decode_image, image_to_tensor, ImageLimits, PreprocessError};
+use crate::options::CaptionOptions;
+
+/// Most frames pulled out of a single video.
+const MAX_VIDEO_FRAMES: usize = 500;
+
+/// ffmpeg is killed when extracting the frames takes longer than this.
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Frames are scaled down to at most this width before captioning; BLIP only sees 384 pixels.
+const FRAME_MAX_WIDTH: u32 = 768;
+
+/// Demuxers ffmpeg may pick for an upload. Playlists and lists like HLS or concat are left out,
+/// they would have ffmpeg open whatever files or URLs they name.
+const INPUT_FORMATS: &str = "mov,mp4,matroska,webm,avi,flv,mpegts,mpeg,ogg,gif,yuv4mpegpipe";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SubtitleFormat {
+    #[default]
+    Vtt,
+    Srt,
+    Json,
+}
+
+impl SubtitleFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Vtt => "text/vtt",
+            Self::Srt => "application/x-subrip",
+            Self::Json => "application/json",
+        }
+    }
+}
+
+/// How frames are picked from a video and how the resulting timeline is written.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VideoOptions {
+    /// Caption one frame every this many seconds.
+    pub interval_secs: f64,
+    /// When set, caption the first frame and every scene change whose ffmpeg scene score exceeds
+    /// this threshold (0 to 1) instead of sampling at a fixed interval.
+    pub scene_threshold: Option<f64>,
+    pub format: SubtitleFormat,
+    #[serde(flatten)]
+    pub caption: CaptionOptions,
+}
+
+impl Default for VideoOptions {
+    fn default() -> Self {
+        Self {
+            interval_secs: 2.0,
+            scene_threshold: None,
+            format: SubtitleFormat::Vtt,
+            caption: CaptionOptions::default(),
+        }
+    }
+}
+
+impl VideoOptions {
+    /// Rejects the caption options that only make sense for a single image upload.
+    fn check(&self) -> Result<(), PreprocessError> {
+        let caption = &self.caption;
+        let image_only = [
+            ("regions", !caption.regions.is_empty()),
+            ("tiles", caption.tiles.is_some()),
+            ("frames", caption.frames.is_some()),
+            ("best_of", caption.best_of.is_some()),
+            ("index", caption.index),
+            ("channel", caption.channel.is_some()),
+            ("image_id", caption.image_id.is_some()),
+        ];
+        match image_only.iter().find(|(_, set)| *set) {
+            Some((name, _)) => Err(PreprocessError::InvalidRequest(format!("{name} is not supported for videos"))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// One subtitle: a caption shown from `start_ms` until `end_ms`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Cue {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub caption: String,
+}
+
+struct ExtractedFrame {
+    timestamp_ms: u64,
+    path: PathBuf,
+}
+
+/// Parses `HH:MM:SS.ss` as printed by ffmpeg into milliseconds.
+fn parse_clock(clock: &str) -> Option<u64> {
+    let mut parts = clock.trim().split(':');
+    let hours: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    Some(((hours * 3600.0 + minutes * 60.0 + seconds) * 1000.0).round() as u64)
+}
+
+/// Runs ffmpeg to dump the selected frames as PNGs into `workdir`. The `showinfo` filter logs the
+/// presentation time of every frame it lets through, which is how frames get their timestamps.
+/// Also returns the container duration when ffmpeg reports one.
+fn extract_frames(ffmpeg: &Path, video: &Path, options: &VideoOptions, workdir: &Path) -> anyhow::Result<(Vec<ExtractedFrame>, Option<u64>)> {
+    let select = match options.scene_threshold {
+        Some(threshold) if (0.0..=1.0).contains(&threshold) => format!("select='eq(n\\,0)+gt(scene\\,{threshold})'"),
+        Some(threshold) => anyhow::bail!("scene threshold must be between 0 and 1, got {threshold}"),
+        None if options.interval_secs > 0.0 => format!("fps=1/{}", options.interval_secs),
+        None => anyhow::bail!("interval must be positive, got {}", options.interval_secs),
+    };
+    let filter = format!("{select},scale='min({FRAME_MAX_WIDTH},iw)':-2,showinfo");
+    let mut child = Command::new(ffmpeg)
+        .arg("-hide_banner")
+        .arg("-nostdin")
+        .args(["-protocol_whitelist", "file", "-format_whitelist", INPUT_FORMATS])
+        .arg("-i")
+        .arg(video)
+        .args(["-vf", &filter, "-fps_mode", "vfr", "-frames:v", &MAX_VIDEO_FRAMES.to_string()])
+        .arg(workdir.join("%06d.png"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("could not run {}: {e}", ffmpeg.display()))?;
+
+    // drain the log on the side so ffmpeg never blocks on a full pipe while we wait for it
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let reader = std::thread::spawn(move || {
+        let mut log = Vec::new();
+        stderr.read_to_end(&mut log).map(|_| log)
+    });
+    let deadline = Instant::now() + FFMPEG_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("ffmpeg did not finish within {} seconds", FFMPEG_TIMEOUT.as_secs());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let log = reader.join().expect("the ffmpeg log reader never panics")?;
+    let log = String::from_utf8_lossy(&log);
+    if !status.success() {
+        let tail: Vec<_> = log.lines().rev().take(5).collect();
+        anyhow::bail!("ffmpeg failed: {}", tail.into_iter().rev().collect::<Vec<_>>().join("\n"));
+    }
+
+    let duration_ms = log
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Duration: "))
+        .and_then(|rest| parse_clock(rest.split(',').next()?));
+    let timestamps = log.lines().filter(|line| line.contains("Parsed_showinfo")).filter_map(|line| {
+        let rest = &line[line.find("pts_time:")? + "pts_time:".len()..];
+        let seconds: f64 = rest.split_whitespace().next()?.parse().ok()?;
+        Some((seconds * 1000.0).round() as u64)
+    });
+    let frames = timestamps
+        .enumerate()
+        .map(|(i, timestamp_ms)| ExtractedFrame {
+            timestamp_ms,
+            path: workdir.join(format!("{:06}.png", i + 1)),
+        })
+        .filter(|frame| frame.path.exists())
+        .collect();
+    Ok((frames, duration_ms))
+}
+
+/// Turns per-frame captions into cues: each frame's caption lasts until the next frame, the last
+/// one until the end of the video, and consecutive identical captions merge into a single cue.
+fn build_cues(captions: Vec<(u64, String)>, duration_ms: Option<u64>, interval_ms: u64) -> Vec<Cue> {
+    let mut cues: Vec<Cue> = Vec::new();
+    let ends: Vec<u64> = captions
+        .iter()
+        .skip(1)
+        .map(|(start, _)| *start)
+        .chain(captions.last().map(|(start, _)| duration_ms.unwrap_or(start + interval_ms).max(*start)))
+        .collect();
+    for ((start_ms, caption), end_ms) in captions.into_iter().zip(ends) {
+        match cues.last_mut() {
+            Some(last) if last.caption == caption => last.end_ms = end_ms,
+            _ => cues.push(Cue { start_ms, end_ms, caption }),
+        }
+    }
+    cues
+}
+
+/// Extracts frames from the video at `path` with ffmpeg, captions each of them with BLIP and
+/// returns the merged timeline.
+pub fn caption_video(ffmpeg: &Path, path: &Path, options: &VideoOptions, limits: &ImageLimits) -> anyhow::Result<Vec<Cue>> {
+    options.check()?;
+    let workdir = tempfile::tempdir()?;
+    let (frames, duration_ms) = extract_frames(ffmpeg, path, options, workdir.path())?;
+    if frames.is_empty() {
+        anyhow::bail!("no frames could be extracted from the video");
+    }
+    // stdout is reserved for the rendered subtitles of the video command
+    eprintln!("extracted {} frames from {}", frames.len(), path.display());
+
+    let mut captioner = Captioner::load(options.caption.model.as_deref(), options.caption.quantized)?;
+    captioner.check_options(&options.caption)?;
+    let preprocess = &options.caption.preprocess;
+    let mut captions = Vec::with_capacity(frames.len());
+    for chunk in frames.chunks(MAX_ENCODE_BATCH) {
+        let inputs = chunk
+            .iter()
+            .map(|frame| {
+                let (img, _) = decode_image(&std::fs::read(&frame.path)?, preprocess, limits)?;
+                Ok(image_to_tensor(&img, preprocess, captioner.image_spec())?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let embeds = captioner.encode_batch(&inputs)?;
+        for (i, frame) in chunk.iter().enumerate() {
+            let caption = captioner.generate(&embeds.narrow(0, i, 1)?, &options.caption, |_| Ok(()))?;
+            eprintln!("{:>8} ms: {caption}", frame.timestamp_ms);
+            captions.push((frame.timestamp_ms, caption));
+        }
+    }
+    let interval_ms = (options.interval_secs * 1000.0) as u64;
+    Ok(build_cues(captions, duration_ms, interval_ms))
+}
+
+fn format_timestamp(ms: u64, separator: char) -> String {
+    let (hours, ms) = (ms / 3_600_000, ms % 3_600_000);
+    let (minutes, ms) = (ms / 60_000, ms % 60_000);
+    let (seconds, ms) = (ms / 1000, ms % 1000);
+    format!("{hours:02}:{minutes:02}:{seconds:02}{separator}{ms:03}")
+}
+
+/// Writes the timeline as WebVTT, SRT or a JSON array of cues.
+pub fn render_cues(cues: &[Cue], format: SubtitleFormat) -> String {
+    let mut out = String::new();
+    match format {
+        SubtitleFormat::Vtt => {
+            out.push_str("WEBVTT\n\n");
+            for cue in cues {
+                out += &format!(
+                    "{} --> {}\n{}\n\n",
+                    format_timestamp(cue.start_ms, '.'),
+                    format_timestamp(cue.end_ms, '.'),
+                    cue.caption
+                );
+            }
+        }
+        SubtitleFormat::Srt => {
+            for (i, cue) in cues.iter().enumerate() {
+                out += &format!(
+                    "{}\n{} --> {}\n{}\n\n",
+                    i + 1,
+                    format_timestamp(cue.start_ms, ','),
+                    format_timestamp(cue.end_ms, ','),
+                    cue.caption
+                );
+            }
+        }
+        SubtitleFormat::Json => out = serde_json::to_string(cues).expect("cues always serialize"),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(start_ms: u64, end_ms: u64, caption: &str) -> Cue {
+        Cue { start_ms, end_ms, caption: caption.to_string() }
+    }
+
+    fn captions(frames: &[(u64, &str)]) -> Vec<(u64, String)> {
+        frames.iter().map(|&(ms, caption)| (ms, caption.to_string())).collect()
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(0, '.'), "00:00:00.000");
+        assert_eq!(format_timestamp(3_723_004, ','), "01:02:03,004");
+    }
+
+    #[test]
+    fn parses_ffmpeg_clock() {
+        assert_eq!(parse_clock("00:01:02.50"), Some(62_500));
+        assert_eq!(parse_clock(" 01:00:00.00"), Some(3_600_000));
+        assert_eq!(parse_clock("N/A"), None);
+    }
+
+    #[test]
+    fn cues_last_until_the_next_frame_and_the_end() {
+        let cues = build_cues(captions(&[(0, "a cat"), (2000, "a dog")]), Some(5000), 2000);
+        assert_eq!(cues.len(), 2);
+        assert_eq!((cues[0].start_ms, cues[0].end_ms), (0, 2000));
+        assert_eq!((cues[1].start_ms, cues[1].end_ms), (2000, 5000));
+    }
+
+    #[test]
+    fn last_cue_falls_back_to_the_interval() {
+        let cues = build_cues(captions(&[(0, "a cat"), (2000, "a dog")]), None, 2000);
+        assert_eq!(cues[1].end_ms, 4000);
+        // a duration shorter than the last timestamp never gives a negative cue
+        let cues = build_cues(captions(&[(0, "a cat"), (2000, "a dog")]), Some(1500), 2000);
+        assert_eq!(cues[1].end_ms, 2000);
+    }
+
+    #[test]
+    fn identical_neighbours_merge() {
+        let frames = captions(&[(0, "a cat"), (2000, "a cat"), (4000, "a dog"), (6000, "a cat")]);
+        let cues = build_cues(frames, Some(8000), 2000);
+        let spans: Vec<_> = cues.iter().map(|cue| (cue.start_ms, cue.end_ms, cue.caption.as_str())).collect();
+        assert_eq!(spans, vec![(0, 4000, "a cat"), (4000, 6000, "a dog"), (6000, 8000, "a cat")]);
+    }
+
+    #[test]
+    fn renders_vtt() {
+        let cues = [cue(0, 1500, "a cat"), cue(1500, 3000, "a dog")];
+        assert_eq!(
+            render_cues(&cues, SubtitleFormat::Vtt),
+            "WEBVTT\n\n00:00:00.000 --> 00:00:01.500\na cat\n\n00:00:01.500 --> 00:00:03.000\na dog\n\n"
+        );
+    }
+
+    #[test]
+    fn renders_srt() {
+        let cues = [cue(0, 1500, "a cat"), cue(1500, 3000, "a dog")];
+        assert_eq!(
+            render_cues(&cues, SubtitleFormat::Srt),
+            "1\n00:00:00,000 --> 00:00:01,500\na cat\n\n2\n00:00:01,500 --> 00:00:03,000\na dog\n\n"
+        );
+    }
+
+    #[test]
+    fn renders_json() {
+        let cues = [cue(0, 1500, "a cat")];
+        let json: serde_json::Value = serde_json::from_str(&render_cues(&cues, SubtitleFormat::Json)).unwrap();
+        assert_eq!(json, serde_json::json!([{ "start_ms": 0, "end_ms": 1500, "caption": "a cat" }]));
+    }
+
+    #[test]
+    fn extracts_frames_at_scene_changes() {
+        let ffmpeg = Path::new("ffmpeg");
+        if Command::new(ffmpeg).arg("-version").output().is_err() {
+            eprintln!("ffmpeg is not installed, skipping");
+            return;
+        }
+        // four frames at one per second, two dark ones followed by two bright ones
+        let clip = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/two_scenes.y4m");
+        let workdir = tempfile::tempdir().unwrap();
+
+        let options = VideoOptions { interval_secs: 1.0, ..Default::default() };
+        let (frames, _) = extract_frames(ffmpeg, &clip, &options, workdir.path()).unwrap();
+        assert_eq!(frames.len(), 4);
+        assert!(frames.windows(2).all(|pair| pair[0].timestamp_ms < pair[1].timestamp_ms));
+        assert!(frames.iter().all(|frame| frame.path.exists()));
+
+        let workdir = tempfile::tempdir().unwrap();
+        let options = VideoOptions { scene_threshold: Some(0.5), ..Default::default() };
+        let (frames, _) = extract_frames(ffmpeg, &clip, &options, workdir.path()).unwrap();
+        let timestamps: Vec<_> = frames.iter().map(|frame| frame.timestamp_ms).collect();
+        assert_eq!(timestamps, vec![0, 2000]);
+    }
+
+    #[test]
+    fn rejects_image_only_options() {
+        assert!(VideoOptions::default().check().is_ok());
+        let mut options = VideoOptions::default();
+        options.caption.best_of = Some(4);
+        let message = options.check().unwrap_err().to_string();
+        assert!(message.contains("best_of"), "{message}");
+        let options: VideoOptions = serde_json::from_str(r#"{ "regions": [{ "x": 0, "y": 0, "width": 1, "height": 1 }] }"#).unwrap();
+        assert!(options.check().is_err());
+    }
+
+    #[test]
+    fn rejects_playlists() {
+        let ffmpeg = Path::new("ffmpeg");
+        if Command::new(ffmpeg).arg("-version").output().is_err() {
+            eprintln!("ffmpeg is not installed, skipping");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let clip = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/two_scenes.y4m");
+        let playlist = dir.path().join("upload");
+        std::fs::write(&playlist, format!("ffconcat version 1.0\nfile '{}'\n", clip.display())).unwrap();
+        let workdir = tempfile::tempdir().unwrap();
+        assert!(extract_frames(ffmpeg, &playlist, &VideoOptions::default(), workdir.path()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_sampling() {
+        let workdir = tempfile::tempdir().unwrap();
+        let clip = Path::new("unused.mp4");
+        let options = VideoOptions { interval_secs: 0.0, ..Default::default() };
+        assert!(extract_frames(Path::new("ffmpeg"), clip, &options, workdir.path()).is_err());
+        let options = VideoOptions { scene_threshold: Some(1.5), ..Default::default() };
+        assert!(extract_frames(Path::new("ffmpeg"), clip, &options, workdir.path()).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use axum::extract::ws::Message;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use crate::blip_vqa::{VqaOptions, VqaResult};
+use crate::live::LiveSettings;
+use crate::models;
+use crate::options::{CaptionOptions, CaptionResult};
+
+/// Version of the JSON message protocol spoken over `/ws`. Bump this whenever a message or event
+/// changes shape in a way old clients would not understand.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Messages a client can send as text frames. Every message is a JSON object with a `type` tag
+/// and a `"v": 1` naming the protocol version it was written against.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Ask for a caption. The image is either inlined as base64 (optionally as a `data:` URL),
+    /// referenced by the `image_id` option of an earlier caption or, when neither is given, sent
+    /// as the very next binary frame.
+    Caption {
+        id: String,
+        #[serde(default)]
+        options: CaptionOptions,
+        #[serde(default)]
+        image: Option<String>,
+    },
+    /// Ask a question about an image, which is inlined or sent as the next binary frame like for
+    /// `caption`. The answer streams back as `token` events followed by an `answer` event.
+    Vqa {
+        id: String,
+        options: VqaOptions,
+        #[serde(default)]
+        image: Option<String>,
+    },
+    /// Switch the connection into live mode: every following binary frame is a video frame for
+    /// session `id`, captioned at most `fps` times per second with stale frames dropped.
+    LiveStart {
+        id: String,
+        #[serde(flatten)]
+        settings: LiveSettings,
+    },
+    LiveStop { id: String },
+    /// Start a chunked upload of `size` bytes; see `chunked_upload` for the chunk frame layout.
+    UploadBegin {
+        id: String,
+        size: usize,
+        content_type: String,
+        #[serde(default)]
+        options: CaptionOptions,
+    },
+    /// Finish a chunked upload. `sha256` is the hex digest of the whole image; once it checks out
+    /// the image is captioned like a regular `caption` request with the same id.
+    UploadEnd { id: String, sha256: String },
+    /// Watch the captions published to `channel`, including ones other clients asked for. The
+    /// `all` channel carries every caption and needs the admin token on the upgrade request.
+    Subscribe { channel: String },
+    Unsubscribe { channel: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientEnvelope {
+    #[serde(default)]
+    v: Option<u32>,
+    #[serde(flatten)]
+    message: ClientMessage,
+}
+
+/// Events the server sends back. Everything tied to a request echoes its `id`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// First frame on every connection, before the server reads anything from the client.
+    Hello { protocol_version: u32, capabilities: Capabilities },
+    Token { id: String, text: String },
+    Progress { id: String, stage: Stage },
+    Done {
+        id: String,
+        #[serde(flatten)]
+        result: CaptionResult,
+    },
+    Answer {
+        id: String,
+        #[serde(flatten)]
+        result: VqaResult,
+    },
+    /// A new rolling caption for a live session. Only sent when the scene changed; `dropped`
+    /// counts the frames skipped since the previous live caption.
+    LiveCaption { id: String, frame: u64, caption: String, dropped: u64 },
+    /// An event published to a channel this connection subscribed to. `source` names the
+    /// transport the caption was requested over, `event` is what that requester saw.
+    Channel { channel: String, source: String, event: Box<ServerEvent> },
+    /// This subscriber fell behind and `skipped` events on `channel` were dropped for it.
+    Lagged { channel: String, skipped: u64 },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        message: String,
+    },
+}
+
+/// What this server supports, announced in the `hello` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub messages: Vec<String>,
+    pub image_transports: Vec<String>,
+    /// Names accepted in the `model` caption option.
+    pub models: Vec<String>,
+    pub max_in_flight: usize,
+    pub max_upload_bytes: usize,
+    /// Seconds between server pings, 0 when heartbeats are disabled.
+    pub heartbeat_secs: u64,
+    /// Seconds without requests before the server closes the socket, 0 when disabled.
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    LoadingModel,
+    Preprocessing,
+    Encoding,
+    Generating,
+}
+
+#[derive(Serialize)]
+struct ServerEnvelope<'a> {
+    v: u32,
+    #[serde(flatten)]
+    event: &'a ServerEvent,
+}
+
+impl ServerEvent {
+    pub fn hello(max_in_flight: usize, max_upload_bytes: usize, heartbeat_secs: u64, idle_timeout_secs: u64) -> Self {
+        Self::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities {
+                messages: vec![
+                    "caption".to_string(),
+                    "vqa".to_string(),
+                    "live_start".to_string(),
+                    "live_stop".to_string(),
+                    "upload_begin".to_string(),
+                    "upload_end".to_string(),
+                    "subscribe".to_string(),
+                    "unsubscribe".to_string(),
+                ],
+                image_transports: vec!["binary".to_string(), "base64".to_string(), "chunked".to_string()],
+                models: models::registry().names().map(String::from).collect(),
+                max_in_flight,
+                max_upload_bytes,
+                heartbeat_secs,
+                idle_timeout_secs,
+            },
+        }
+    }
+
+    pub fn error(id: Option<&str>, message: impl ToString) -> Self {
+        Self::Error {
+            id: id.map(String::from),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        let envelope = ServerEnvelope { v: PROTOCOL_VERSION, event: self };
+        Message::Text(serde_json::to_string(&envelope).expect("server events always serialize"))
+    }
+}
+
+/// Parses a text frame into a [`ClientMessage`], rejecting messages that do not name the
+/// protocol version or were written for another one.
+pub fn parse_client_message(text: &str) -> anyhow::Result<ClientMessage> {
+    let envelope: ClientEnvelope = serde_json::from_str(text)?;
+    match envelope.v {
+        Some(PROTOCOL_VERSION) => Ok(envelope.message),
+        Some(v) => anyhow::bail!("unsupported protocol version {v}, server speaks {PROTOCOL_VERSION}"),
+        None => anyhow::bail!("messages must carry the protocol version, \"v\": {PROTOCOL_VERSION}"),
+    }
+}
+
+/// Decodes an inline base64 image, accepting either bare base64 or a `data:<mime>;base64,` URL.
+/// Images over `max_bytes` are rejected, before decoding when the length of the text gives them
+/// away.
+pub fn decode_inline_image(data: &str, max_bytes: usize) -> anyhow::Result<Vec<u8>> {
+    let data = match data.strip_prefix("data:") {
+        Some(url) => match url.split_once(";base64,") {
+            Some((_, payload)) => payload,
+            None => anyhow::bail!("only base64 data URLs are supported"),
+        },
+        None => data,
+    };
+    let data = data.trim();
+    let too_large = |size: usize| anyhow::anyhow!("inline image of {size} bytes exceeds the limit of {max_bytes} bytes");
+    // the estimate rounds up to whole 3 byte groups, padding can make up the difference
+    if base64::decoded_len_estimate(data.len()) > max_bytes + 2 {
+        return Err(too_large(base64::decoded_len_estimate(data.len())));
+    }
+    let image = base64::engine::general_purpose::STANDARD.decode(data)?;
+    if image.len() > max_bytes {
+        return Err(too_large(image.len()));
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_name_the_protocol_version() {
+        let message = parse_client_message(r#"{ "v": 1, "type": "caption", "id": "a" }"#).unwrap();
+        assert!(matches!(message, ClientMessage::Caption { id, image: None, .. } if id == "a"));
+
+        let unknown = parse_client_message(r#"{ "v": 2, "type": "caption", "id": "a" }"#).unwrap_err();
+        assert!(unknown.to_string().contains("unsupported protocol version 2"), "{unknown}");
+        let missing = parse_client_message(r#"{ "type": "caption", "id": "a" }"#).unwrap_err();
+        assert!(missing.to_string().contains("must carry the protocol version"), "{missing}");
+    }
+
+    #[test]
+    fn malformed_envelopes_are_rejected() {
+        for text in [
+            "Hello World",
+            r#"{ "v": 1 }"#,
+            r#"{ "v": 1, "type": "shout", "id": "a" }"#,
+            r#"{ "v": 1, "type": "caption" }"#,
+            r#"{ "v": "1", "type": "caption", "id": "a" }"#,
+            r#"{ "v": 1, "type": "caption", "id": "a", "options": { "temperature": "hot" } }"#,
+        ] {
+            assert!(parse_client_message(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn inline_images_are_bare_base64_or_data_urls() {
+        assert_eq!(decode_inline_image("aGVsbG8=", 16).unwrap(), b"hello");
+        assert_eq!(decode_inline_image(" aGVsbG8=\n", 16).unwrap(), b"hello");
+        assert_eq!(decode_inline_image("data:image/png;base64,aGVsbG8=", 16).unwrap(), b"hello");
+        assert!(decode_inline_image("data:image/svg+xml,<svg/>", 16).is_err());
+        assert!(decode_inline_image("not base64!", 16).is_err());
+    }
+
+    #[test]
+    fn oversize_inline_images_are_rejected() {
+        assert_eq!(decode_inline_image("aGVsbG8=", 5).unwrap(), b"hello");
+        let error = decode_inline_image("aGVsbG8=", 4).unwrap_err();
+        assert!(error.to_string().contains("exceeds the limit of 4 bytes"), "{error}");
+        // far too long to be worth decoding at all
+        let huge = "A".repeat(4 * 1024);
+        assert!(decode_inline_image(&huge, 16).is_err());
+    }
+}